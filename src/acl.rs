@@ -0,0 +1,276 @@
+use std::{net::IpAddr, str::FromStr};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::handshake::{addr::SocksAddr, cmd::SocksCmd};
+
+#[derive(Error, Debug)]
+#[error("connection not allowed by access rules")]
+pub struct AccessDenied;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Ordered access rules, the first matching rule decides.
+///
+/// Requests not matched by any rule get the default action.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Acl {
+    pub default: Action,
+    pub rules: Vec<Rule>,
+}
+
+/// Rule matching a request when all of its non-empty conditions match.
+///
+/// Each condition matches if any of its entries does.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub action: Action,
+    #[serde(default)]
+    pub sources: Vec<IpNet>,
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub destinations: Vec<IpNet>,
+    #[serde(default)]
+    pub domains: Vec<DomainPattern>,
+    #[serde(default)]
+    pub ports: Vec<PortRange>,
+    #[serde(default)]
+    pub commands: Vec<SocksCmd>,
+}
+
+/// Everything the rules can be evaluated against.
+#[derive(Clone, Copy, Debug)]
+pub struct AclRequest<'a> {
+    pub client: IpAddr,
+    /// Authenticated user, if the client authenticated.
+    pub user: Option<&'a str>,
+    pub cmd: SocksCmd,
+    /// Destination as requested by the client.
+    pub addr: &'a SocksAddr,
+    /// Destination IP the request would be sent to, after resolution.
+    ///
+    /// Unknown when an upstream proxy resolves the domain name, rules with
+    /// destination networks do not match then.
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+}
+
+impl Acl {
+    pub fn check(&self, req: &AclRequest) -> Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(req))
+            .map_or(self.default, |rule| rule.action)
+    }
+
+    /// Checks a request whose destination is not known yet, e.g. a UDP association.
+    ///
+    /// The request is allowed when the rules allow at least some destination, which have
+    /// to be checked once they are known. It is denied when a rule without destination
+    /// conditions denies it, or when nothing but the default decides and that denies.
+    pub fn check_without_destination(
+        &self,
+        client: IpAddr,
+        user: Option<&str>,
+        cmd: SocksCmd,
+    ) -> Action {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches_client(client, user, cmd))
+            // a rule denying some destinations leaves the others to the following rules
+            .find(|rule| !rule.has_destination() || rule.action == Action::Allow)
+            .map_or(self.default, |rule| rule.action)
+    }
+}
+
+impl Rule {
+    fn has_destination(&self) -> bool {
+        !self.destinations.is_empty() || !self.domains.is_empty() || !self.ports.is_empty()
+    }
+
+    fn matches_client(&self, client: IpAddr, user: Option<&str>, cmd: SocksCmd) -> bool {
+        let client = client.to_canonical();
+        (self.sources.is_empty() || self.sources.iter().any(|net| net.contains(&client)))
+            && (self.users.is_empty()
+                || user.is_some_and(|user| self.users.iter().any(|u| u == user)))
+            && (self.commands.is_empty() || self.commands.contains(&cmd))
+    }
+
+    fn matches(&self, req: &AclRequest) -> bool {
+        let ip = req.ip.map(|ip| ip.to_canonical());
+        self.matches_client(req.client, req.user, req.cmd)
+            && (self.destinations.is_empty()
+                || ip.is_some_and(|ip| self.destinations.iter().any(|net| net.contains(&ip))))
+            && (self.domains.is_empty()
+                || matches!(req.addr, SocksAddr::Domain(domain)
+                    if self.domains.iter().any(|pattern| pattern.matches(domain))))
+            && (self.ports.is_empty() || self.ports.iter().any(|range| range.contains(req.port)))
+    }
+}
+
+/// Domain name, optionally starting with `*.` to match any of its subdomains.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DomainPattern {
+    suffix: String,
+    wildcard: bool,
+}
+
+impl DomainPattern {
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if !self.wildcard {
+            return domain == self.suffix;
+        }
+        domain
+            .strip_suffix(&self.suffix)
+            .is_some_and(|sub| sub.ends_with('.'))
+    }
+}
+
+impl FromStr for DomainPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (suffix, wildcard) = match s.strip_prefix("*.") {
+            Some(suffix) => (suffix, true),
+            None => (s, false),
+        };
+        let suffix = suffix.trim_end_matches('.').to_ascii_lowercase();
+        if suffix.is_empty() || suffix.contains('*') {
+            return Err(format!("invalid domain pattern {:?}", s));
+        }
+        Ok(Self { suffix, wildcard })
+    }
+}
+
+impl TryFrom<String> for DomainPattern {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Inclusive range of ports, written as a single port or as `first-last`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "PortRangeRepr")]
+pub struct PortRange {
+    first: u16,
+    last: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.first <= port && port <= self.last
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid port range {:?}", s);
+        let (first, last) = match s.split_once('-') {
+            Some((first, last)) => (first.trim(), last.trim()),
+            None => (s.trim(), s.trim()),
+        };
+        let first: u16 = first.parse().map_err(|_| invalid())?;
+        let last: u16 = last.parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        Ok(Self { first, last })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRangeRepr {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<PortRangeRepr> for PortRange {
+    type Error = String;
+
+    fn try_from(value: PortRangeRepr) -> Result<Self, Self::Error> {
+        match value {
+            PortRangeRepr::Port(port) => Ok(Self {
+                first: port,
+                last: port,
+            }),
+            PortRangeRepr::Range(range) => range.parse(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(config: &str) -> Acl {
+        toml::from_str(config).unwrap()
+    }
+
+    fn check_udp(acl: &Acl) -> Action {
+        let client = "192.0.2.1".parse().unwrap();
+        acl.check_without_destination(client, None, SocksCmd::UdpAssociate)
+    }
+
+    #[test]
+    fn unknown_destination_gets_default() {
+        assert_eq!(check_udp(&acl("")), Action::Allow);
+        assert_eq!(check_udp(&acl(r#"default = "deny""#)), Action::Deny);
+    }
+
+    #[test]
+    fn unknown_destination_allowed_when_some_destination_is() {
+        let acl = acl(r#"
+            default = "deny"
+            [[rules]]
+            action = "deny"
+            ports = [53]
+            [[rules]]
+            action = "allow"
+            destinations = ["198.51.100.0/24"]
+            "#);
+        assert_eq!(check_udp(&acl), Action::Allow);
+    }
+
+    #[test]
+    fn unknown_destination_denied_by_client_rule() {
+        let acl = acl(r#"
+            [[rules]]
+            action = "deny"
+            sources = ["192.0.2.0/24"]
+            [[rules]]
+            action = "allow"
+            destinations = ["198.51.100.0/24"]
+            "#);
+        assert_eq!(check_udp(&acl), Action::Deny);
+    }
+
+    #[test]
+    fn destination_rules_of_other_clients_do_not_count() {
+        let acl = acl(r#"
+            default = "deny"
+            [[rules]]
+            action = "allow"
+            users = ["alice"]
+            destinations = ["198.51.100.0/24"]
+            "#);
+        assert_eq!(check_udp(&acl), Action::Deny);
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+
+/// Decides whether the credentials sent by a client are valid.
+///
+/// The server consults it during the username/password sub-negotiation (RFC 1929).
+pub trait CredentialChecker: Send + Sync {
+    fn check(&self, username: &str, password: &str) -> bool;
+}
+
+/// Fixed set of users known up front, e.g. from the command line.
+#[derive(Clone, Debug, Default)]
+pub struct StaticCredentials {
+    users: HashMap<String, String>,
+}
+
+impl StaticCredentials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, username: impl Into<String>, password: impl Into<String>) {
+        self.users.insert(username.into(), password.into());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+impl FromIterator<(String, String)> for StaticCredentials {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        Self {
+            users: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl CredentialChecker for StaticCredentials {
+    fn check(&self, username: &str, password: &str) -> bool {
+        self.users
+            .get(username)
+            .is_some_and(|expected| expected == password)
+    }
+}
@@ -1,17 +1,18 @@
-use std::{net::Ipv4Addr, str::FromStr};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
 
 use clap::Parser;
 use shoes::{
     cli::ClientCli,
-    client::ClientConnectMsg,
-    handshake::{
-        addr_type::AddrType, cmd::SocksCmd, method::SocksMethod, reply::SocksReply,
-        reply_field::ReplyField, version::SocksVersion, SocksHandshake,
-    },
+    client::{self, Auth, ClientError, Socks5Stream},
+    handshake::{addr::SocksAddr, cmd::SocksCmd, udp::UdpHeader},
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    net::{TcpStream, UdpSocket},
+    time::timeout,
 };
 
 #[tokio::main]
@@ -22,37 +23,39 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = ClientCli::parse();
     let port = args.port.unwrap_or(default_port);
     let host = args.host.unwrap_or(default_host);
-
-    let mut socket = TcpStream::connect(format!("{}:{}", host, port)).await?;
-
-    let connect_msg = ClientConnectMsg::new(SocksVersion::V5, 1, vec![SocksMethod::NoAuth]);
-
-    socket.write(&connect_msg.to_request()).await?;
-    let mut buf = [0_u8; 1024];
-    socket.read(&mut buf).await?;
-    let version: SocksVersion = buf[0].try_into()?;
-    let _method: SocksMethod = buf[1].try_into()?;
-
-    let hs_req = SocksHandshake {
-        version,
-        cmd: SocksCmd::Connect,
-        addr: Ipv4Addr::from_str(&host).expect("should be a valid IPv4 addr"),
-        port: target_port,
-        atyp: AddrType::Ipv4,
+    let auth = match &args.user {
+        Some(user) => {
+            let (username, password) = user
+                .split_once(':')
+                .ok_or("expected --user as 'username:password'")?;
+            Auth::user_pass(username, password)
+        }
+        None => Auth::NoAuth,
     };
+    let proxy = format!("{}:{}", host, port);
 
-    socket.write(&hs_req.to_request()).await?;
-    socket.read(&mut buf).await?;
-    let socks_reply = SocksReply::parse(&buf)?;
-
-    println!("socks reply: {:?}", socks_reply);
-    if socks_reply.rep != ReplyField::Succeeded {
-        println!(
-            "Handshake failed, exiting. Server replied: {:?}",
-            socks_reply.rep
-        );
-        return Ok(());
+    if args.udp {
+        let socket = TcpStream::connect(&proxy).await?;
+        return udp_associate(socket, &auth, &host, target_port).await;
     }
+    if args.bind {
+        let socket = TcpStream::connect(&proxy).await?;
+        return bind(socket, &auth, &host, target_port).await;
+    }
+
+    let target = (host.parse::<SocksAddr>()?, target_port);
+    let mut stream = match Socks5Stream::connect(&proxy, target, &auth).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!("Handshake failed, exiting: {}", err);
+            return Ok(());
+        }
+    };
+    let (bnd_addr, bnd_port) = stream.bound_addr();
+    println!(
+        "Connected, server reached the target from {}:{}",
+        bnd_addr, bnd_port
+    );
 
     let mut input = String::new();
     println!("Input the data which will be sent to server:");
@@ -61,10 +64,98 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     match std::io::stdin().read_line(&mut input) {
         Ok(_) => {
             println!("Sending data to server: {:?}", input);
-            socket.write(input.as_bytes()).await?;
+            stream.write_all(input.as_bytes()).await?;
         }
         Err(e) => panic!("Failed to read user input: {:?}", e),
     };
 
     Ok(())
 }
+
+async fn udp_associate(
+    mut socket: TcpStream,
+    auth: &Auth,
+    host: &str,
+    target_port: u16,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // we do not know our UDP address upfront, so we let the server accept any
+    let unspecified = SocksAddr::Ipv4(Ipv4Addr::UNSPECIFIED);
+    let socks_reply =
+        match client::handshake(&mut socket, SocksCmd::UdpAssociate, unspecified, 0, auth).await {
+            Ok(reply) => reply,
+            Err(err) => {
+                println!("UDP associate failed, exiting: {}", err);
+                return Ok(());
+            }
+        };
+    println!("socks reply: {:?}", socks_reply);
+    let relay_ip = socks_reply
+        .bnd_addr
+        .ip()
+        .ok_or("UDP relay address is not an IP address")?;
+    let relay_addr = SocketAddr::new(relay_ip, socks_reply.bnd_port);
+    let udp_socket = if relay_ip.is_ipv4() {
+        UdpSocket::bind("0.0.0.0:0").await?
+    } else {
+        UdpSocket::bind("[::]:0").await?
+    };
+
+    let mut input = String::new();
+    println!("Input the data which will be sent to server over UDP:");
+    std::io::stdin().read_line(&mut input)?;
+
+    let mut datagram = UdpHeader::new(host.parse::<SocksAddr>()?, target_port).to_bytes();
+    datagram.extend_from_slice(input.as_bytes());
+    println!("Sending datagram via relay at {}", relay_addr);
+    udp_socket.send_to(&datagram, relay_addr).await?;
+
+    let mut datagram_buf = vec![0_u8; 65535];
+    let (n_read, _) = timeout(
+        Duration::from_secs(5),
+        udp_socket.recv_from(&mut datagram_buf),
+    )
+    .await??;
+    let (header, header_len) = UdpHeader::parse(&datagram_buf[..n_read])?;
+    println!(
+        "Received from {}:{}: {:?}",
+        header.addr,
+        header.port,
+        String::from_utf8_lossy(&datagram_buf[header_len..n_read])
+    );
+
+    Ok(())
+}
+
+async fn bind(
+    mut socket: TcpStream,
+    auth: &Auth,
+    host: &str,
+    target_port: u16,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let peer = host.parse::<SocksAddr>()?;
+
+    // the first reply carries the address the peer should connect to,
+    // the second one arrives once it did
+    let replies = async {
+        let bound = client::handshake(&mut socket, SocksCmd::Bind, peer, target_port, auth).await?;
+        println!("socks reply: {:?}", bound);
+        let accepted = client::read_reply(&mut socket).await?;
+        println!("socks reply: {:?}", accepted);
+        Ok::<_, ClientError>(())
+    };
+    if let Err(err) = replies.await {
+        println!("Bind failed, exiting: {}", err);
+        return Ok(());
+    }
+
+    let mut buf = [0_u8; 1024];
+
+    loop {
+        let n_read = socket.read(&mut buf).await?;
+        if n_read == 0 {
+            println!("Peer closed the connection");
+            return Ok(());
+        }
+        println!("Received: {:?}", String::from_utf8_lossy(&buf[..n_read]));
+    }
+}
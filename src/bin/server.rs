@@ -1,20 +1,68 @@
 use clap::Parser;
-use tokio::net::TcpListener;
+use tokio::signal;
 
-use shoes::{cli::Cli, server};
+use shoes::{
+    cli::Cli,
+    config::{Config, ConfigError},
+    server::ServerBuilder,
+};
 use tracing::debug;
 
+fn load_config(args: &Cli) -> Result<Config, ConfigError> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    config.apply_cli(args)?;
+    config.validate()?;
+    Ok(config)
+}
+
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
-
-    let default_port = 7474;
     let args = Cli::parse();
-    let port = args.port.unwrap_or(default_port);
-    let addr = format!("127.0.0.1:{}", port);
+    let config = match load_config(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("shoes: {}", err);
+            std::process::exit(2);
+        }
+    };
+
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level()?)
+        .init();
+
+    let mut builder = ServerBuilder::new()
+        .with_limits(config.limits())
+        .with_drain_timeout(config.drain_timeout());
+    for (addr, settings) in config.listeners()? {
+        debug!("Starting server on {}", addr);
+        builder = builder.bind_with_settings(addr, settings);
+    }
+    let server = builder.start().await?;
 
-    debug!("Starting server on {}", addr);
-    let listener = TcpListener::bind(addr).await?;
-    server::run(listener).await;
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        shutdown.shutdown();
+    });
+    server.wait().await?;
     Ok(())
 }
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = signal::ctrl_c().await;
+}
@@ -1,8 +1,10 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
 use clap::Parser;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::net::TcpListener;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
-use shoes::cli::Cli;
+use shoes::cli::TargetCli;
 use tracing::{error, info};
 
 #[tokio::main]
@@ -10,11 +12,19 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
 
     let default_port = 6666;
-    let args = Cli::parse();
+    let args = TargetCli::parse();
     let port = args.port.unwrap_or(default_port);
+    let ip = args.address.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    if let Some(addr) = args.connect_to {
+        return connect_to(addr).await;
+    }
+    if args.udp {
+        return run_udp(SocketAddr::new(ip, port)).await;
+    }
 
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
-    info!("Running on port {}", port);
+    let listener = TcpListener::bind(SocketAddr::new(ip, port)).await?;
+    info!("Running on {}:{}", ip, port);
 
     loop {
         let (mut socket, _addr) = listener.accept().await?;
@@ -49,3 +59,30 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
         });
     }
 }
+
+async fn run_udp(addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let socket = UdpSocket::bind(addr).await?;
+    info!("Echoing UDP datagrams on {}", addr);
+
+    let mut buf = vec![0_u8; 65535];
+    loop {
+        let (n_read, from) = socket.recv_from(&mut buf).await?;
+        info!(
+            "Received from {}: {:?}",
+            from,
+            String::from_utf8_lossy(&buf[..n_read])
+        );
+        socket.send_to(&buf[..n_read], from).await?;
+    }
+}
+
+async fn connect_to(addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let mut socket = TcpStream::connect(addr).await?;
+    info!("Connected to {}", addr);
+
+    let mut input = String::new();
+    println!("Input the data which will be sent to {}:", addr);
+    std::io::stdin().read_line(&mut input)?;
+    socket.write_all(input.as_bytes()).await?;
+    Ok(())
+}
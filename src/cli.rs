@@ -1,9 +1,81 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
 use clap::Parser;
 
 #[derive(Parser, Debug)]
 pub struct Cli {
+    /// Path to a TOML configuration file, command line flags override its values
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Port to listen on, only allowed when the config file has at most one listener
     #[clap(short, long)]
     pub port: Option<u16>,
+
+    /// Address to listen on, e.g. 0.0.0.0 or :: for all interfaces (repeatable)
+    #[clap(short, long = "address")]
+    pub addresses: Vec<IpAddr>,
+
+    /// Require username/password authentication, given as 'username:password' (repeatable)
+    #[clap(short, long = "user")]
+    pub users: Vec<String>,
+
+    /// One of trace, debug, info, warn or error
+    #[clap(long)]
+    pub log_level: Option<String>,
+
+    /// Connections open at the same time, further clients wait until one is closed
+    #[clap(long)]
+    pub max_connections: Option<usize>,
+
+    /// Connections a single client IP address may have open at the same time
+    #[clap(long)]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Seconds a client gets to complete the handshake
+    #[clap(long)]
+    pub handshake_timeout: Option<u64>,
+
+    /// Seconds to wait for a connection to the target host
+    #[clap(long)]
+    pub connect_timeout: Option<u64>,
+
+    /// Seconds after which relayed connections with no traffic are closed
+    #[clap(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// Seconds open connections get to finish after SIGINT or SIGTERM before they are closed
+    #[clap(long)]
+    pub drain_timeout: Option<u64>,
+
+    /// Local address to make outbound connections from, picked round-robin (repeatable)
+    #[clap(long = "source-address")]
+    pub source_addresses: Vec<IpAddr>,
+
+    /// Network interface to make outbound connections through, Linux only
+    #[clap(long)]
+    pub interface: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct TargetCli {
+    #[clap(short, long)]
+    pub port: Option<u16>,
+
+    /// Address to listen on, e.g. 0.0.0.0 or :: for all interfaces
+    #[clap(short, long)]
+    pub address: Option<IpAddr>,
+
+    /// Echo UDP datagrams instead of reading lines over TCP
+    #[clap(long)]
+    pub udp: bool,
+
+    /// Connect to the given address and send it a line, e.g. to the address bound by BIND
+    #[clap(long)]
+    pub connect_to: Option<SocketAddr>,
 }
 
 #[derive(Parser, Debug)]
@@ -13,4 +85,16 @@ pub struct ClientCli {
 
     #[clap(short, long)]
     pub host: Option<String>,
+
+    /// Send a datagram through a UDP association instead of connecting over TCP
+    #[clap(long)]
+    pub udp: bool,
+
+    /// Ask the server to accept an inbound connection from host and print what it sends
+    #[clap(long)]
+    pub bind: bool,
+
+    /// Authenticate with the server, given as 'username:password'
+    #[clap(short, long)]
+    pub user: Option<String>,
 }
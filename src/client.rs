@@ -1,4 +1,69 @@
-use crate::handshake::{method::SocksMethod, version::SocksVersion};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::handshake::{
+    addr::SocksAddr,
+    addr_type::AddrType,
+    cmd::SocksCmd,
+    error::HandshakeError,
+    method::SocksMethod,
+    reply::SocksReply,
+    reply_field::ReplyField,
+    user_pass::{self, UserPassAuth, UserPassStatus, USER_PASS_VERSION},
+    version::SocksVersion,
+    SocksHandshake,
+};
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("invalid message from proxy: {0}")]
+    Protocol(#[from] HandshakeError),
+
+    #[error("proxy accepts none of the offered methods")]
+    NoAcceptableMethod,
+
+    #[error("proxy selected {0:?} which was not offered")]
+    UnexpectedMethod(SocksMethod),
+
+    #[error("proxy rejected the credentials")]
+    AuthFailed,
+
+    #[error("proxy rejected the request: {0:?}")]
+    Rejected(ReplyField),
+}
+
+/// Authentication offered to the proxy.
+#[derive(Clone, Debug, Default)]
+pub enum Auth {
+    #[default]
+    NoAuth,
+    UserPass(UserPassAuth),
+}
+
+impl Auth {
+    pub fn user_pass(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self::UserPass(UserPassAuth::new(username, password))
+    }
+
+    fn method(&self) -> SocksMethod {
+        match self {
+            Self::NoAuth => SocksMethod::NoAuth,
+            Self::UserPass(_) => SocksMethod::UsernamePassword,
+        }
+    }
+}
 
 // #[derive(Debug)]
 pub struct ClientConnectMsg {
@@ -26,3 +91,188 @@ impl ClientConnectMsg {
         req
     }
 }
+
+/// Stream to a target host, tunneled through a SOCKS5 proxy.
+///
+/// Once connected, everything written to the stream is relayed to the target
+/// and everything the target sends can be read from it.
+#[derive(Debug)]
+pub struct Socks5Stream<S = TcpStream> {
+    inner: S,
+    bnd_addr: SocksAddr,
+    bnd_port: u16,
+}
+
+impl Socks5Stream<TcpStream> {
+    /// Connects to the proxy and asks it to connect to `target`.
+    pub async fn connect(
+        proxy: impl ToSocketAddrs,
+        target: (SocksAddr, u16),
+        auth: &Auth,
+    ) -> Result<Self, ClientError> {
+        let socket = TcpStream::connect(proxy).await?;
+        Self::connect_with_socket(socket, target, auth).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Stream<S> {
+    /// Same as `connect`, over an already established connection to the proxy.
+    pub async fn connect_with_socket(
+        mut socket: S,
+        target: (SocksAddr, u16),
+        auth: &Auth,
+    ) -> Result<Self, ClientError> {
+        let (addr, port) = target;
+        let reply = handshake(&mut socket, SocksCmd::Connect, addr, port, auth).await?;
+        Ok(Self {
+            inner: socket,
+            bnd_addr: reply.bnd_addr,
+            bnd_port: reply.bnd_port,
+        })
+    }
+
+    /// Address and port the proxy uses to reach the target.
+    pub fn bound_addr(&self) -> (&SocksAddr, u16) {
+        (&self.bnd_addr, self.bnd_port)
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Socks5Stream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Socks5Stream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Negotiates the method, authenticates and sends the request.
+///
+/// Returns the first reply of the proxy if it reports success, BIND requests get a second
+/// reply once the peer connected, it can be read with `read_reply`.
+pub async fn handshake<S>(
+    socket: &mut S,
+    cmd: SocksCmd,
+    addr: SocksAddr,
+    port: u16,
+    auth: &Auth,
+) -> Result<SocksReply, ClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    // a domain name built by hand may be too long to be sent
+    if !addr.is_valid() {
+        return Err(HandshakeError::InvalidDomainName.into());
+    }
+    let method = auth.method();
+    let greeting = ClientConnectMsg::new(SocksVersion::V5, 1, vec![method]);
+    socket.write_all(&greeting.to_request()).await?;
+
+    let mut buf = [0_u8; 2];
+    socket.read_exact(&mut buf).await?;
+    let version: SocksVersion = buf[0].try_into()?;
+    if version != SocksVersion::V5 {
+        return Err(HandshakeError::UnsupportedVersion.into());
+    }
+    match SocksMethod::from(buf[1]) {
+        SocksMethod::NoAcceptableMethod => return Err(ClientError::NoAcceptableMethod),
+        selected if selected != method => return Err(ClientError::UnexpectedMethod(selected)),
+        _ => {}
+    }
+
+    if let Auth::UserPass(credentials) = auth {
+        authenticate(socket, credentials).await?;
+    }
+
+    let request = SocksHandshake {
+        version,
+        cmd,
+        addr,
+        port,
+    };
+    socket.write_all(&request.to_request()).await?;
+    read_reply(socket).await
+}
+
+async fn authenticate<S>(socket: &mut S, credentials: &UserPassAuth) -> Result<(), ClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    for field in [&credentials.username, &credentials.password] {
+        if !user_pass::is_valid_field(field) {
+            return Err(HandshakeError::FieldTooLong.into());
+        }
+    }
+    socket.write_all(&credentials.to_request()).await?;
+
+    let mut buf = [0_u8; 2];
+    socket.read_exact(&mut buf).await?;
+    if buf[0] != USER_PASS_VERSION {
+        return Err(HandshakeError::UnsupportedAuthVersion.into());
+    }
+    match UserPassStatus::from(buf[1]) {
+        UserPassStatus::Success => Ok(()),
+        UserPassStatus::Failure => Err(ClientError::AuthFailed),
+    }
+}
+
+/// Reads a single reply, failing if it does not report success.
+///
+/// Reads exactly the reply, data the target sends right after it stays in the socket.
+pub async fn read_reply<S>(socket: &mut S) -> Result<SocksReply, ClientError>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let mut buf = vec![0_u8; 4];
+    socket.read_exact(&mut buf).await?;
+    let addr_len = match AddrType::try_from(buf[3])? {
+        AddrType::Ipv4 => 4,
+        AddrType::Ipv6 => 16,
+        AddrType::DomainName => {
+            let len = socket.read_u8().await?;
+            buf.push(len);
+            len as usize
+        }
+    };
+    let header_len = buf.len();
+    // address followed by the port
+    buf.resize(header_len + addr_len + 2, 0);
+    socket.read_exact(&mut buf[header_len..]).await?;
+
+    let reply = SocksReply::parse(&buf)?;
+    if reply.rep != ReplyField::Succeeded {
+        return Err(ClientError::Rejected(reply.rep));
+    }
+    Ok(reply)
+}
@@ -0,0 +1,764 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::Level;
+
+use crate::{
+    acl::{Acl, DomainPattern, PortRange},
+    auth::StaticCredentials,
+    cli::Cli,
+    client::Auth,
+    dialer::{
+        Dialer, DirectDialer, FamilyPreference, HappyEyeballs, SourceDialer, SourceSelection,
+        UpstreamDialer, DEFAULT_ATTEMPT_DELAY,
+    },
+    handshake::{cmd::SocksCmd, method::SocksMethod, user_pass},
+    http,
+    resolver::{
+        dns::{
+            DnsResolver, NameServer, DEFAULT_CACHE_SIZE, DEFAULT_NEGATIVE_TTL,
+            DEFAULT_QUERY_TIMEOUT,
+        },
+        HostsResolver, Resolver, SystemResolver,
+    },
+    server::{
+        Limits, Settings, Timeouts, DEFAULT_CONNECT_TIMEOUT, DEFAULT_DRAIN_TIMEOUT,
+        DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_MAX_CONNECTIONS,
+    },
+    upstream::{Proxy, ProxyProtocol, Route, Upstream},
+};
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+use crate::dialer::InterfaceDialer;
+
+pub const DEFAULT_PORT: u16 = 7474;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("invalid value for `{key}`: {reason}")]
+    Invalid { key: String, reason: String },
+}
+
+impl ConfigError {
+    fn invalid(key: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::Invalid {
+            key: key.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Server configuration as read from a TOML file.
+///
+/// Every key is optional, missing keys fall back to the same defaults as the command line.
+/// Top level `auth`, `commands` and `acl` apply to every listener that does not set its own.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub auth: AuthConfig,
+    pub commands: Option<Vec<SocksCmd>>,
+    pub acl: Acl,
+    pub upstream: UpstreamConfig,
+    pub outbound: OutboundConfig,
+    pub dns: DnsConfig,
+    pub timeouts: TimeoutsConfig,
+    pub limits: LimitsConfig,
+    pub shutdown: ShutdownConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    pub auth: Option<AuthConfig>,
+    pub commands: Option<Vec<SocksCmd>>,
+    pub acl: Option<Acl>,
+}
+
+impl ListenerConfig {
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            auth: None,
+            commands: None,
+            acl: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Defaults to username/password when users are configured, no authentication otherwise.
+    pub methods: Option<Vec<AuthMethod>>,
+    pub users: Vec<UserConfig>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    NoAuth,
+    UsernamePassword,
+}
+
+impl From<AuthMethod> for SocksMethod {
+    fn from(method: AuthMethod) -> Self {
+        match method {
+            AuthMethod::NoAuth => SocksMethod::NoAuth,
+            AuthMethod::UsernamePassword => SocksMethod::UsernamePassword,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub username: String,
+    pub password: String,
+}
+
+/// Upstream proxies and the routes choosing which destinations go through them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub proxies: Vec<ProxyConfig>,
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub name: String,
+    pub protocol: ProxyProtocol,
+    /// Written as `host:port`, IPv6 addresses in brackets.
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Names of the proxies to go through in order, an empty list connects directly.
+    pub via: Vec<String>,
+    #[serde(default)]
+    pub destinations: Vec<IpNet>,
+    #[serde(default)]
+    pub domains: Vec<DomainPattern>,
+    #[serde(default)]
+    pub ports: Vec<PortRange>,
+}
+
+/// How outbound connections are made.
+///
+/// Local addresses and the network interface default to letting the OS choose,
+/// they cannot be set together.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConfig {
+    /// One of these of the target's IP family is picked according to `selection`.
+    pub source_addresses: Vec<IpAddr>,
+    pub selection: SourceSelection,
+    /// Name of the interface to bind to with SO_BINDTODEVICE, only supported on Linux.
+    pub interface: Option<String>,
+    /// Family tried first for targets with both IPv4 and IPv6 addresses.
+    pub family_preference: FamilyPreference,
+    /// Milliseconds a connection attempt gets before the next address is tried alongside it.
+    pub attempt_delay_ms: u64,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            source_addresses: Vec::new(),
+            selection: SourceSelection::default(),
+            interface: None,
+            family_preference: FamilyPreference::default(),
+            attempt_delay_ms: DEFAULT_ATTEMPT_DELAY.as_millis() as u64,
+        }
+    }
+}
+
+/// Resolver for domain names requested by clients and of upstream proxies.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    /// Name servers asked in order, e.g. `1.1.1.1`, `[2606:4700:4700::1111]:53` or
+    /// `tcp://9.9.9.9`. The system resolver is used when none are set.
+    pub servers: Vec<String>,
+    /// Milliseconds a single name server gets to answer before the next one is asked.
+    pub query_timeout_ms: u64,
+    /// Seconds names without addresses are cached for at most.
+    pub negative_ttl: u64,
+    /// Answers kept in the cache, 0 disables caching.
+    pub cache_size: usize,
+    /// Addresses returned for these names without asking any name server.
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            query_timeout_ms: DEFAULT_QUERY_TIMEOUT.as_millis() as u64,
+            negative_ttl: DEFAULT_NEGATIVE_TTL.as_secs(),
+            cache_size: DEFAULT_CACHE_SIZE,
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+/// Timeouts in seconds, applied to every listener.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub handshake: u64,
+    pub connect: u64,
+    /// Relayed connections are never closed for being idle if not set.
+    pub idle: Option<u64>,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            handshake: DEFAULT_HANDSHAKE_TIMEOUT.as_secs(),
+            connect: DEFAULT_CONNECT_TIMEOUT.as_secs(),
+            idle: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Connections open at the same time across all listeners.
+    pub max_connections: usize,
+    pub max_connections_per_ip: Option<usize>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_ip: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds open connections get to finish before they are closed.
+    pub drain_timeout: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&content).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Overrides values from the file with the ones given on the command line.
+    ///
+    /// Addresses given on the command line replace all configured listeners,
+    /// users replace the ones in the top level `auth` section.
+    pub fn apply_cli(&mut self, cli: &Cli) -> Result<(), ConfigError> {
+        if !cli.addresses.is_empty() {
+            let port = cli.port.unwrap_or(DEFAULT_PORT);
+            self.listeners = cli
+                .addresses
+                .iter()
+                .map(|ip| ListenerConfig::new(SocketAddr::new(*ip, port)))
+                .collect();
+        } else if let Some(port) = cli.port {
+            // with several listeners it is unclear which one the port is meant for
+            match self.listeners.as_mut_slice() {
+                [] => {
+                    let mut address = default_listen_addr();
+                    address.set_port(port);
+                    self.listeners.push(ListenerConfig::new(address));
+                }
+                [listener] => listener.address.set_port(port),
+                _ => {
+                    return Err(ConfigError::invalid(
+                        "--port",
+                        "cannot be applied to several configured listeners, \
+                         use --address or change the ports in the config file",
+                    ))
+                }
+            }
+        }
+
+        if !cli.users.is_empty() {
+            self.auth.users = cli
+                .users
+                .iter()
+                .map(|user| match user.split_once(':') {
+                    Some((username, password)) => Ok(UserConfig {
+                        username: username.to_string(),
+                        password: password.to_string(),
+                    }),
+                    None => Err(ConfigError::invalid(
+                        "--user",
+                        format!("expected 'username:password', got {:?}", user),
+                    )),
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if let Some(level) = &cli.log_level {
+            self.log.level = Some(level.clone());
+        }
+        if let Some(timeout) = cli.handshake_timeout {
+            self.timeouts.handshake = timeout;
+        }
+        if let Some(timeout) = cli.connect_timeout {
+            self.timeouts.connect = timeout;
+        }
+        if let Some(timeout) = cli.idle_timeout {
+            self.timeouts.idle = Some(timeout);
+        }
+        if let Some(max) = cli.max_connections {
+            self.limits.max_connections = max;
+        }
+        if let Some(max) = cli.max_connections_per_ip {
+            self.limits.max_connections_per_ip = Some(max);
+        }
+        if let Some(timeout) = cli.drain_timeout {
+            self.shutdown.drain_timeout = timeout;
+        }
+        if !cli.source_addresses.is_empty() {
+            self.outbound.source_addresses = cli.source_addresses.clone();
+        }
+        if let Some(interface) = &cli.interface {
+            self.outbound.interface = Some(interface.clone());
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        validate_auth(&self.auth, "auth")?;
+        validate_commands(&self.commands, "commands")?;
+
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let key = format!("listeners[{}]", i);
+            if let Some(auth) = &listener.auth {
+                validate_auth(auth, &format!("{}.auth", key))?;
+            }
+            validate_commands(&listener.commands, &format!("{}.commands", key))?;
+
+            if self.listeners[..i]
+                .iter()
+                .any(|other| other.address == listener.address)
+            {
+                return Err(ConfigError::invalid(
+                    format!("{}.address", key),
+                    format!("{} is already used by another listener", listener.address),
+                ));
+            }
+        }
+
+        for (key, timeout) in [
+            ("timeouts.handshake", Some(self.timeouts.handshake)),
+            ("timeouts.connect", Some(self.timeouts.connect)),
+            ("timeouts.idle", self.timeouts.idle),
+        ] {
+            if timeout == Some(0) {
+                return Err(ConfigError::invalid(key, "has to be at least one second"));
+            }
+        }
+
+        if self.limits.max_connections == 0 {
+            return Err(ConfigError::invalid(
+                "limits.max_connections",
+                "at least one connection has to be allowed",
+            ));
+        }
+        if self.limits.max_connections_per_ip == Some(0) {
+            return Err(ConfigError::invalid(
+                "limits.max_connections_per_ip",
+                "at least one connection has to be allowed",
+            ));
+        }
+
+        let resolver = self.resolver()?;
+        self.dialer(&resolver)?;
+        self.log_level()?;
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Result<Level, ConfigError> {
+        match &self.log.level {
+            Some(level) => level.parse().map_err(|_| {
+                ConfigError::invalid("log.level", format!("unknown level {:?}", level))
+            }),
+            None => Ok(Level::INFO),
+        }
+    }
+
+    fn settings(
+        &self,
+        auth: &AuthConfig,
+        commands: &Option<Vec<SocksCmd>>,
+        acl: &Acl,
+        dialer: &Arc<dyn Dialer>,
+        resolver: &Arc<dyn Resolver>,
+    ) -> Settings {
+        let credentials: StaticCredentials = auth
+            .users
+            .iter()
+            .map(|user| (user.username.clone(), user.password.clone()))
+            .collect();
+        let defaults = Settings::default();
+
+        Settings {
+            methods: auth_methods(auth).into_iter().map(Into::into).collect(),
+            credentials: if credentials.is_empty() {
+                None
+            } else {
+                Some(Arc::new(credentials))
+            },
+            commands: commands.clone().unwrap_or(defaults.commands),
+            acl: acl.clone(),
+            timeouts: Timeouts {
+                handshake: Duration::from_secs(self.timeouts.handshake),
+                connect: Duration::from_secs(self.timeouts.connect),
+                idle: self.timeouts.idle.map(Duration::from_secs),
+            },
+            dialer: dialer.clone(),
+            resolver: resolver.clone(),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_connections: self.limits.max_connections,
+            max_connections_per_ip: self.limits.max_connections_per_ip,
+        }
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown.drain_timeout)
+    }
+
+    /// Addresses to listen on with the settings for each of them.
+    pub fn listeners(&self) -> Result<Vec<(SocketAddr, Settings)>, ConfigError> {
+        let resolver = self.resolver()?;
+        let dialer = self.dialer(&resolver)?;
+        if self.listeners.is_empty() {
+            return Ok(vec![(
+                default_listen_addr(),
+                self.settings(&self.auth, &self.commands, &self.acl, &dialer, &resolver),
+            )]);
+        }
+
+        Ok(self
+            .listeners
+            .iter()
+            .map(|listener| {
+                let auth = listener.auth.as_ref().unwrap_or(&self.auth);
+                let commands = listener.commands.as_ref().or(self.commands.as_ref());
+                let acl = listener.acl.as_ref().unwrap_or(&self.acl);
+                (
+                    listener.address,
+                    self.settings(auth, &commands.cloned(), acl, &dialer, &resolver),
+                )
+            })
+            .collect())
+    }
+
+    /// Dialer making the outbound connections, through upstream proxies if routes are set.
+    pub fn dialer(&self, resolver: &Arc<dyn Resolver>) -> Result<Arc<dyn Dialer>, ConfigError> {
+        let upstream = self.upstream()?;
+        let direct = self.outbound_dialer()?;
+        if upstream.routes.is_empty() {
+            return Ok(direct);
+        }
+        let dialer = UpstreamDialer::new(upstream, direct).with_resolver(resolver.clone());
+        Ok(Arc::new(dialer))
+    }
+
+    /// Resolver for domain names, the system one unless name servers or hosts are set.
+    pub fn resolver(&self) -> Result<Arc<dyn Resolver>, ConfigError> {
+        let dns = &self.dns;
+        if dns.query_timeout_ms == 0 {
+            return Err(ConfigError::invalid(
+                "dns.query_timeout_ms",
+                "has to be at least one millisecond",
+            ));
+        }
+        let servers = dns
+            .servers
+            .iter()
+            .enumerate()
+            .map(|(i, server)| {
+                server.parse::<NameServer>().map_err(|err| {
+                    ConfigError::invalid(format!("dns.servers[{}]", i), err.to_string())
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (name, ips) in dns.hosts.iter() {
+            if ips.is_empty() {
+                return Err(ConfigError::invalid(
+                    format!("dns.hosts.{:?}", name),
+                    "at least one address has to be given",
+                ));
+            }
+        }
+
+        let resolver: Arc<dyn Resolver> = if servers.is_empty() {
+            Arc::new(SystemResolver)
+        } else {
+            Arc::new(
+                DnsResolver::new(servers)
+                    .with_query_timeout(Duration::from_millis(dns.query_timeout_ms))
+                    .with_negative_ttl(Duration::from_secs(dns.negative_ttl))
+                    .with_cache_size(dns.cache_size),
+            )
+        };
+        if dns.hosts.is_empty() {
+            return Ok(resolver);
+        }
+        Ok(Arc::new(HostsResolver::new(dns.hosts.clone(), resolver)))
+    }
+
+    fn outbound_dialer(&self) -> Result<Arc<dyn Dialer>, ConfigError> {
+        let outbound = &self.outbound;
+        // RFC 8305 puts the lower bound at 10 ms
+        if outbound.attempt_delay_ms < 10 {
+            return Err(ConfigError::invalid(
+                "outbound.attempt_delay_ms",
+                "has to be at least 10 milliseconds",
+            ));
+        }
+        let happy_eyeballs = HappyEyeballs {
+            preference: outbound.family_preference,
+            attempt_delay: Duration::from_millis(outbound.attempt_delay_ms),
+        };
+
+        match (&outbound.interface, outbound.source_addresses.is_empty()) {
+            (Some(_), false) => Err(ConfigError::invalid(
+                "outbound.interface",
+                "cannot be combined with `outbound.source_addresses`",
+            )),
+            (Some(interface), true) => interface_dialer(interface, happy_eyeballs),
+            (None, false) => Ok(Arc::new(
+                SourceDialer::pool(outbound.source_addresses.clone(), outbound.selection)
+                    .with_happy_eyeballs(happy_eyeballs),
+            )),
+            (None, true) => Ok(Arc::new(
+                DirectDialer::new().with_happy_eyeballs(happy_eyeballs),
+            )),
+        }
+    }
+
+    pub fn upstream(&self) -> Result<Upstream, ConfigError> {
+        let mut proxies: HashMap<&str, Arc<Proxy>> = HashMap::new();
+        for (i, proxy) in self.upstream.proxies.iter().enumerate() {
+            let key = format!("upstream.proxies[{}]", i);
+            if proxies.contains_key(proxy.name.as_str()) {
+                return Err(ConfigError::invalid(
+                    format!("{}.name", key),
+                    format!("{:?} is already used by another proxy", proxy.name),
+                ));
+            }
+            proxies.insert(&proxy.name, Arc::new(proxy_from_config(proxy, &key)?));
+        }
+
+        let routes = self
+            .upstream
+            .routes
+            .iter()
+            .enumerate()
+            .map(|(i, route)| {
+                let via = route
+                    .via
+                    .iter()
+                    .map(|name| {
+                        proxies.get(name.as_str()).cloned().ok_or_else(|| {
+                            ConfigError::invalid(
+                                format!("upstream.routes[{}].via", i),
+                                format!("no proxy named {:?}", name),
+                            )
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(Route {
+                    via,
+                    destinations: route.destinations.clone(),
+                    domains: route.domains.clone(),
+                    ports: route.ports.clone(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Upstream { routes })
+    }
+}
+
+fn default_listen_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT)
+}
+
+fn validate_auth(auth: &AuthConfig, key: &str) -> Result<(), ConfigError> {
+    let methods = auth_methods(auth);
+    if methods.is_empty() {
+        return Err(ConfigError::invalid(
+            format!("{}.methods", key),
+            "at least one method has to be enabled",
+        ));
+    }
+    if methods.contains(&AuthMethod::UsernamePassword) && auth.users.is_empty() {
+        return Err(ConfigError::invalid(
+            format!("{}.methods", key),
+            format!(
+                "username_password needs at least one entry in {}.users",
+                key
+            ),
+        ));
+    }
+    if !methods.contains(&AuthMethod::UsernamePassword) && !auth.users.is_empty() {
+        return Err(ConfigError::invalid(
+            format!("{}.users", key),
+            format!(
+                "users are configured but username_password is not in {}.methods",
+                key
+            ),
+        ));
+    }
+
+    for (i, user) in auth.users.iter().enumerate() {
+        let key = format!("{}.users[{}]", key, i);
+        validate_credentials(&user.username, &user.password, &key)?;
+    }
+    Ok(())
+}
+
+fn validate_credentials(username: &str, password: &str, key: &str) -> Result<(), ConfigError> {
+    for (field, value) in [("username", username), ("password", password)] {
+        if !user_pass::is_valid_field(value) {
+            return Err(ConfigError::invalid(
+                format!("{}.{}", key, field),
+                "has to be 1 to 255 bytes long",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn proxy_from_config(proxy: &ProxyConfig, key: &str) -> Result<Proxy, ConfigError> {
+    let (addr, port) = http::parse_authority(&proxy.address).ok_or_else(|| {
+        ConfigError::invalid(
+            format!("{}.address", key),
+            format!("expected 'host:port', got {:?}", proxy.address),
+        )
+    })?;
+
+    let auth = match (&proxy.username, &proxy.password) {
+        (Some(username), Some(password)) => {
+            validate_credentials(username, password, key)?;
+            Auth::user_pass(username, password)
+        }
+        (None, None) => Auth::NoAuth,
+        _ => {
+            return Err(ConfigError::invalid(
+                key,
+                "username and password have to be set together",
+            ))
+        }
+    };
+
+    Ok(Proxy {
+        name: proxy.name.clone(),
+        protocol: proxy.protocol,
+        addr,
+        port,
+        auth,
+    })
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn interface_dialer(
+    interface: &str,
+    happy_eyeballs: HappyEyeballs,
+) -> Result<Arc<dyn Dialer>, ConfigError> {
+    if interface.is_empty() {
+        return Err(ConfigError::invalid(
+            "outbound.interface",
+            "cannot be empty",
+        ));
+    }
+    Ok(Arc::new(
+        InterfaceDialer::new(interface).with_happy_eyeballs(happy_eyeballs),
+    ))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn interface_dialer(
+    _interface: &str,
+    _happy_eyeballs: HappyEyeballs,
+) -> Result<Arc<dyn Dialer>, ConfigError> {
+    Err(ConfigError::invalid(
+        "outbound.interface",
+        "binding to an interface is not supported on this platform",
+    ))
+}
+
+fn validate_commands(commands: &Option<Vec<SocksCmd>>, key: &str) -> Result<(), ConfigError> {
+    match commands {
+        Some(commands) if commands.is_empty() => Err(ConfigError::invalid(
+            key,
+            "at least one command has to be allowed",
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn auth_methods(auth: &AuthConfig) -> Vec<AuthMethod> {
+    match &auth.methods {
+        Some(methods) => methods.clone(),
+        None if auth.users.is_empty() => vec![AuthMethod::NoAuth],
+        None => vec![AuthMethod::UsernamePassword],
+    }
+}
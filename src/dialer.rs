@@ -0,0 +1,587 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    error::Error,
+    future::{self, Future},
+    hash::{Hash, Hasher},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpSocket, TcpStream, UdpSocket},
+    time::{self, Instant},
+};
+use tracing::debug;
+
+use crate::{
+    handshake::{addr::SocksAddr, reply_field::ReplyField},
+    relay::BoxStream,
+    resolver::{Resolver, SystemResolver},
+    upstream::{self, Upstream, UpstreamError},
+};
+
+/// Delay between connection attempts RFC 8305 recommends.
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Connection to a target made by a dialer.
+pub struct Outbound {
+    pub stream: BoxStream,
+    /// Local address of the connection, reported to the client as BND.ADDR and BND.PORT.
+    pub local_addr: SocketAddr,
+}
+
+/// Failure to reach a target along with the reply telling the client why.
+#[derive(Error, Debug)]
+#[error("{source}")]
+pub struct DialError {
+    pub rep: ReplyField,
+    source: Box<dyn Error + Send + Sync>,
+}
+
+impl DialError {
+    pub fn new(rep: ReplyField, source: impl Error + Send + Sync + 'static) -> Self {
+        Self {
+            rep,
+            source: Box::new(source),
+        }
+    }
+}
+
+impl From<io::Error> for DialError {
+    fn from(err: io::Error) -> Self {
+        Self::new(ReplyField::from(&err), err)
+    }
+}
+
+impl From<UpstreamError> for DialError {
+    fn from(err: UpstreamError) -> Self {
+        Self::new(ReplyField::from(&err), err)
+    }
+}
+
+/// Makes the outbound connections and sockets for client requests.
+///
+/// Used for CONNECT targets, BIND listeners and the outbound sockets of UDP associations.
+pub trait Dialer: Send + Sync {
+    /// Whether the server resolves the target before calling `connect`.
+    ///
+    /// Dialers that leave resolution to someone else, e.g. an upstream proxy, return `false`.
+    fn resolves_locally(&self, _addr: &SocksAddr, _port: u16) -> bool {
+        true
+    }
+
+    /// Connects to the target of a CONNECT request.
+    ///
+    /// `addrs` are the resolved addresses the access rules allow, in the order the resolver
+    /// returned them, they are empty if the target is not resolved locally.
+    /// `user` is the authenticated user of the client, if any.
+    fn connect<'a>(
+        &'a self,
+        addr: &'a SocksAddr,
+        port: u16,
+        addrs: &'a [SocketAddr],
+        user: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Outbound, DialError>>;
+
+    /// Binds the listener for a BIND request expecting a connection from one of `peer_addrs`.
+    fn bind_listener<'a>(
+        &'a self,
+        peer_addrs: &'a [SocketAddr],
+        fallback_ip: IpAddr,
+        _user: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<TcpListener>> {
+        Box::pin(async move {
+            let local_ip = local_ip_for(peer_addrs, fallback_ip, None).await?;
+            TcpListener::bind((local_ip, 0)).await
+        })
+    }
+
+    /// Binds a socket UDP associations use to send datagrams to IPv4 or IPv6 targets.
+    fn bind_udp<'a>(
+        &'a self,
+        ipv6: bool,
+        _user: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<UdpSocket>> {
+        Box::pin(async move {
+            let ip = unspecified(ipv6);
+            UdpSocket::bind((ip, 0)).await
+        })
+    }
+}
+
+/// IP family tried first when a target has addresses of both.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FamilyPreference {
+    #[default]
+    Ipv6,
+    Ipv4,
+}
+
+/// How connection attempts to the addresses of a target are raced, following RFC 8305.
+///
+/// Addresses are tried alternating between families, starting with the preferred one.
+/// Each attempt gets `attempt_delay` before the next one starts alongside it,
+/// the first connection established wins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HappyEyeballs {
+    pub preference: FamilyPreference,
+    pub attempt_delay: Duration,
+}
+
+impl Default for HappyEyeballs {
+    fn default() -> Self {
+        Self {
+            preference: FamilyPreference::default(),
+            attempt_delay: DEFAULT_ATTEMPT_DELAY,
+        }
+    }
+}
+
+/// Connects straight to the target.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DirectDialer {
+    happy_eyeballs: HappyEyeballs,
+}
+
+impl DirectDialer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_happy_eyeballs(mut self, happy_eyeballs: HappyEyeballs) -> Self {
+        self.happy_eyeballs = happy_eyeballs;
+        self
+    }
+}
+
+impl Dialer for DirectDialer {
+    fn connect<'a>(
+        &'a self,
+        _addr: &'a SocksAddr,
+        _port: u16,
+        addrs: &'a [SocketAddr],
+        _user: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Outbound, DialError>> {
+        Box::pin(async move {
+            let outbound = connect_each(addrs, self.happy_eyeballs, new_tcp_socket).await?;
+            Ok(outbound)
+        })
+    }
+}
+
+/// How a source address is picked from a pool for each outbound connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceSelection {
+    /// Addresses take turns.
+    #[default]
+    RoundRobin,
+    /// Each user always gets the same address, clients without a user take turns.
+    PerUser,
+}
+
+/// Makes every outbound connection from one of the given local addresses.
+///
+/// Only addresses of the target's IP family are considered, targets of a family
+/// with no address in the pool are unreachable.
+#[derive(Debug)]
+pub struct SourceDialer {
+    ips: Vec<IpAddr>,
+    selection: SourceSelection,
+    next: AtomicUsize,
+    happy_eyeballs: HappyEyeballs,
+}
+
+impl SourceDialer {
+    pub fn new(ip: IpAddr) -> Self {
+        Self::pool(vec![ip], SourceSelection::RoundRobin)
+    }
+
+    pub fn pool(ips: Vec<IpAddr>, selection: SourceSelection) -> Self {
+        Self {
+            ips,
+            selection,
+            next: AtomicUsize::new(0),
+            happy_eyeballs: HappyEyeballs::default(),
+        }
+    }
+
+    pub fn with_happy_eyeballs(mut self, happy_eyeballs: HappyEyeballs) -> Self {
+        self.happy_eyeballs = happy_eyeballs;
+        self
+    }
+
+    fn source_for(&self, ipv6: bool, user: Option<&str>) -> io::Result<IpAddr> {
+        if !self.ips.iter().any(|ip| ip.is_ipv6() == ipv6) {
+            return Err(no_source_address());
+        }
+        let index = self.next_index(user);
+        Ok(self.source_at(index, ipv6).expect("family has candidates"))
+    }
+
+    /// Position in the pool for the next connection, the same for both IP families.
+    fn next_index(&self, user: Option<&str>) -> usize {
+        match (self.selection, user) {
+            (SourceSelection::PerUser, Some(user)) => {
+                let mut hasher = DefaultHasher::new();
+                user.hash(&mut hasher);
+                hasher.finish() as usize
+            }
+            _ => self.next.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    fn source_at(&self, index: usize, ipv6: bool) -> Option<IpAddr> {
+        let candidates: Vec<_> = self.ips.iter().filter(|ip| ip.is_ipv6() == ipv6).collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(*candidates[index % candidates.len()])
+    }
+}
+
+fn no_source_address() -> io::Error {
+    io::Error::new(
+        ErrorKind::AddrNotAvailable,
+        "no source address of the target's IP family",
+    )
+}
+
+impl Dialer for SourceDialer {
+    fn connect<'a>(
+        &'a self,
+        _addr: &'a SocksAddr,
+        _port: u16,
+        addrs: &'a [SocketAddr],
+        user: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Outbound, DialError>> {
+        Box::pin(async move {
+            // every attempt of a family uses the same source, retries do not rotate the pool
+            let index = self.next_index(user);
+            let (source_v4, source_v6) =
+                (self.source_at(index, false), self.source_at(index, true));
+            let outbound = connect_each(addrs, self.happy_eyeballs, |addr| {
+                let source = match addr {
+                    SocketAddr::V4(_) => source_v4,
+                    SocketAddr::V6(_) => source_v6,
+                };
+                let source = source.ok_or_else(no_source_address)?;
+                let socket = new_tcp_socket(addr)?;
+                socket.bind(SocketAddr::new(source, 0))?;
+                Ok(socket)
+            })
+            .await?;
+            Ok(outbound)
+        })
+    }
+
+    fn bind_listener<'a>(
+        &'a self,
+        peer_addrs: &'a [SocketAddr],
+        fallback_ip: IpAddr,
+        user: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<TcpListener>> {
+        Box::pin(async move {
+            let ipv6 = match peer_addrs.first() {
+                Some(peer) if !peer.ip().is_unspecified() => peer.is_ipv6(),
+                _ => fallback_ip.to_canonical().is_ipv6(),
+            };
+            let source = self.source_for(ipv6, user)?;
+            TcpListener::bind((source, 0)).await
+        })
+    }
+
+    fn bind_udp<'a>(
+        &'a self,
+        ipv6: bool,
+        user: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<UdpSocket>> {
+        Box::pin(async move {
+            let source = self.source_for(ipv6, user)?;
+            UdpSocket::bind((source, 0)).await
+        })
+    }
+}
+
+/// Makes every outbound connection through the given network interface, with SO_BINDTODEVICE.
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+#[derive(Clone, Debug)]
+pub struct InterfaceDialer {
+    interface: String,
+    happy_eyeballs: HappyEyeballs,
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+impl InterfaceDialer {
+    pub fn new(interface: impl Into<String>) -> Self {
+        Self {
+            interface: interface.into(),
+            happy_eyeballs: HappyEyeballs::default(),
+        }
+    }
+
+    pub fn with_happy_eyeballs(mut self, happy_eyeballs: HappyEyeballs) -> Self {
+        self.happy_eyeballs = happy_eyeballs;
+        self
+    }
+
+    fn bind_device(&self, socket: &impl std::os::unix::io::AsRawFd) -> io::Result<()> {
+        socket2::SockRef::from(socket).bind_device(Some(self.interface.as_bytes()))
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+impl Dialer for InterfaceDialer {
+    fn connect<'a>(
+        &'a self,
+        _addr: &'a SocksAddr,
+        _port: u16,
+        addrs: &'a [SocketAddr],
+        _user: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Outbound, DialError>> {
+        Box::pin(async move {
+            let outbound = connect_each(addrs, self.happy_eyeballs, |addr| {
+                let socket = new_tcp_socket(addr)?;
+                self.bind_device(&socket)?;
+                Ok(socket)
+            })
+            .await?;
+            Ok(outbound)
+        })
+    }
+
+    fn bind_listener<'a>(
+        &'a self,
+        peer_addrs: &'a [SocketAddr],
+        fallback_ip: IpAddr,
+        _user: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<TcpListener>> {
+        Box::pin(async move {
+            let local_ip = local_ip_for(peer_addrs, fallback_ip, Some(&self.interface)).await?;
+            let local_addr = SocketAddr::new(local_ip, 0);
+            let socket = new_tcp_socket(local_addr)?;
+            self.bind_device(&socket)?;
+            socket.bind(local_addr)?;
+            socket.listen(1024)
+        })
+    }
+
+    fn bind_udp<'a>(
+        &'a self,
+        ipv6: bool,
+        _user: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<UdpSocket>> {
+        Box::pin(async move {
+            let addr = SocketAddr::new(unspecified(ipv6), 0);
+            bind_udp_socket(addr, Some(&self.interface))
+        })
+    }
+}
+
+/// Sends CONNECT requests through upstream proxies according to routes.
+///
+/// Destinations no route sends through a proxy, as well as BIND and UDP sockets,
+/// are left to the fallback dialer, which also makes the connections to the first proxies.
+pub struct UpstreamDialer {
+    upstream: Upstream,
+    fallback: Arc<dyn Dialer>,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl UpstreamDialer {
+    pub fn new(upstream: Upstream, fallback: Arc<dyn Dialer>) -> Self {
+        Self {
+            upstream,
+            fallback,
+            resolver: Arc::new(SystemResolver),
+        }
+    }
+
+    /// Resolver for the domain names of the first proxies of the chains.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+}
+
+impl Dialer for UpstreamDialer {
+    fn resolves_locally(&self, addr: &SocksAddr, port: u16) -> bool {
+        match self.upstream.route(addr, port) {
+            Some(_) => false,
+            None => self.fallback.resolves_locally(addr, port),
+        }
+    }
+
+    fn connect<'a>(
+        &'a self,
+        addr: &'a SocksAddr,
+        port: u16,
+        addrs: &'a [SocketAddr],
+        user: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Outbound, DialError>> {
+        match self.upstream.route(addr, port) {
+            Some(chain) => Box::pin(async move {
+                let outbound =
+                    upstream::connect(chain, addr, port, &*self.fallback, &*self.resolver, user)
+                        .await?;
+                Ok(outbound)
+            }),
+            None => self.fallback.connect(addr, port, addrs, user),
+        }
+    }
+
+    fn bind_listener<'a>(
+        &'a self,
+        peer_addrs: &'a [SocketAddr],
+        fallback_ip: IpAddr,
+        user: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<TcpListener>> {
+        self.fallback.bind_listener(peer_addrs, fallback_ip, user)
+    }
+
+    fn bind_udp<'a>(
+        &'a self,
+        ipv6: bool,
+        user: Option<&'a str>,
+    ) -> BoxFuture<'a, io::Result<UdpSocket>> {
+        self.fallback.bind_udp(ipv6, user)
+    }
+}
+
+/// Races connection attempts to the addresses, returning the first connection established.
+async fn connect_each(
+    addrs: &[SocketAddr],
+    happy_eyeballs: HappyEyeballs,
+    new_socket: impl Fn(SocketAddr) -> io::Result<TcpSocket>,
+) -> io::Result<Outbound> {
+    let mut remaining = interleave(addrs, happy_eyeballs.preference).into_iter();
+    let mut attempts: Vec<BoxFuture<'static, io::Result<TcpStream>>> = Vec::new();
+    let mut next_attempt = Instant::now();
+    let mut last_err = None;
+    loop {
+        tokio::select! {
+            (i, res) = first_finished(&mut attempts), if !attempts.is_empty() => {
+                drop(attempts.swap_remove(i));
+                match res {
+                    Ok(stream) => {
+                        let local_addr = stream.local_addr()?;
+                        return Ok(Outbound {
+                            stream: Box::new(stream),
+                            local_addr,
+                        });
+                    }
+                    Err(err) => {
+                        debug!("Connection attempt failed: {}", err);
+                        last_err = Some(err);
+                        // a failed attempt does not have to wait out the delay for the next one
+                        next_attempt = Instant::now();
+                    }
+                }
+            }
+            _ = time::sleep_until(next_attempt), if remaining.len() > 0 => {
+                let addr = remaining.next().expect("remaining addresses are checked");
+                debug!("Attempting to connect to {}", addr);
+                let socket = new_socket(addr);
+                attempts.push(Box::pin(async move { socket?.connect(addr).await }));
+                next_attempt = Instant::now() + happy_eyeballs.attempt_delay;
+            }
+            else => {
+                return Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(ErrorKind::AddrNotAvailable, "no usable address for target")
+                }));
+            }
+        }
+    }
+}
+
+/// Alternates between address families starting with the preferred one, keeping the order
+/// within each family.
+fn interleave(addrs: &[SocketAddr], preference: FamilyPreference) -> Vec<SocketAddr> {
+    let prefer_ipv6 = preference == FamilyPreference::Ipv6;
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == prefer_ipv6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut ordered = Vec::with_capacity(addrs.len());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (first, second) => ordered.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// Waits for the first of the futures to complete, returning its index and output.
+async fn first_finished<T>(futures: &mut [BoxFuture<'_, T>]) -> (usize, T) {
+    future::poll_fn(|cx| {
+        for (i, fut) in futures.iter_mut().enumerate() {
+            if let Poll::Ready(out) = fut.as_mut().poll(cx) {
+                return Poll::Ready((i, out));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+fn new_tcp_socket(addr: SocketAddr) -> io::Result<TcpSocket> {
+    match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+}
+
+fn unspecified(ipv6: bool) -> IpAddr {
+    if ipv6 {
+        Ipv6Addr::UNSPECIFIED.into()
+    } else {
+        Ipv4Addr::UNSPECIFIED.into()
+    }
+}
+
+/// Local address used to reach the peer, so that it can connect back to a listener on it.
+async fn local_ip_for(
+    peer_addrs: &[SocketAddr],
+    fallback_ip: IpAddr,
+    interface: Option<&str>,
+) -> io::Result<IpAddr> {
+    match peer_addrs.first() {
+        Some(peer) if !peer.ip().is_unspecified() => {
+            // connecting a UDP socket sends nothing, it just lets the OS pick the route
+            let probe_addr = SocketAddr::new(unspecified(peer.is_ipv6()), 0);
+            let probe = bind_udp_socket(probe_addr, interface)?;
+            probe.connect(peer).await?;
+            Ok(probe.local_addr()?.ip())
+        }
+        _ => Ok(fallback_ip.to_canonical()),
+    }
+}
+
+fn bind_udp_socket(addr: SocketAddr, interface: Option<&str>) -> io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::DGRAM,
+        None,
+    )?;
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    if let Some(interface) = interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    let _ = interface;
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
@@ -1,26 +1,40 @@
 use bytes::Buf;
 use std::io::Cursor;
 use std::net::Ipv4Addr;
+use std::sync::Arc;
 
+pub mod addr;
 pub mod addr_type;
 pub mod cmd;
 pub mod error;
 pub mod method;
 pub mod reply;
 pub mod reply_field;
+pub mod socks4;
+pub mod udp;
+pub mod user_pass;
 pub mod version;
 
+use crate::auth::CredentialChecker;
+use crate::handshake::addr::SocksAddr;
 use crate::handshake::addr_type::AddrType;
 use crate::handshake::cmd::SocksCmd;
 use crate::handshake::error::HandshakeError;
 use crate::handshake::method::SocksMethod;
+use crate::handshake::reply::SocksReply;
+use crate::handshake::socks4::Socks4Request;
+use crate::handshake::user_pass::{user_pass_reply, UserPassAuth, UserPassStatus};
 use crate::handshake::version::SocksVersion;
 
-pub trait WithIpv4Addr {
-    fn addr(&self) -> Ipv4Addr;
+pub trait WithAddr {
+    fn addr(&self) -> &SocksAddr;
+
+    fn atyp(&self) -> AddrType {
+        self.addr().atyp()
+    }
 
     fn addr_to_bytes(&self) -> Vec<u8> {
-        self.addr().octets().to_vec()
+        self.addr().to_bytes()
     }
 }
 
@@ -36,31 +50,37 @@ pub trait WithPort {
 pub struct SocksHandshake {
     pub version: SocksVersion,
     pub cmd: SocksCmd,
-    pub addr: Ipv4Addr,
+    pub addr: SocksAddr,
     pub port: u16,
-    pub atyp: AddrType, // do we need this one if the information can be derived from addr?
 }
 
 impl SocksHandshake {
-    pub fn to_addr(&self) -> String {
-        format!("{}:{}", self.addr, self.port)
-    }
-
     pub fn to_request(&self) -> Vec<u8> {
-        let mut req: Vec<u8> = vec![];
-        req.push(self.version.into());
-        req.push(self.cmd.into());
-        req.push(0); // reserved
-        req.push(self.atyp.into());
+        if self.version == SocksVersion::V4 {
+            let req = Socks4Request {
+                cmd: self.cmd,
+                addr: self.addr.clone(),
+                port: self.port,
+                user_id: String::new(),
+            };
+            return req.to_request();
+        }
+
+        let mut req: Vec<u8> = vec![
+            self.version.into(),
+            self.cmd.into(),
+            0, // reserved
+            self.atyp().into(),
+        ];
         req.append(&mut self.addr_to_bytes());
         req.append(&mut self.port_to_bytes());
         req
     }
 }
 
-impl WithIpv4Addr for SocksHandshake {
-    fn addr(&self) -> Ipv4Addr {
-        self.addr
+impl WithAddr for SocksHandshake {
+    fn addr(&self) -> &SocksAddr {
+        &self.addr
     }
 }
 impl WithPort for SocksHandshake {
@@ -69,40 +89,138 @@ impl WithPort for SocksHandshake {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub enum HandshakeState {
+    #[default]
     Init,
-    Wait(SocksVersion, Vec<SocksMethod>),
+    // the negotiated method requires a sub-negotiation before the request
+    Auth(SocksVersion, SocksMethod),
+    Wait(SocksVersion, SocksMethod),
     Finished(SocksHandshake),
+    // the reply has to be sent to the client before closing the connection
+    Rejected(HandshakeError),
 }
 
 #[derive(Clone)]
 pub struct HandshakeStateBuilder {
     state: HandshakeState,
+    methods: Vec<SocksMethod>,
+    credentials: Option<Arc<dyn CredentialChecker>>,
+    commands: Vec<SocksCmd>,
+    user: Option<String>,
+}
+
+impl Default for HandshakeStateBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HandshakeStateBuilder {
     pub fn new() -> Self {
         Self {
             state: HandshakeState::Init,
+            methods: vec![SocksMethod::NoAuth],
+            credentials: None,
+            commands: vec![SocksCmd::Connect, SocksCmd::Bind, SocksCmd::UdpAssociate],
+            user: None,
         }
     }
 
+    /// Methods the server is willing to use, in order of preference.
+    pub fn with_methods(mut self, methods: Vec<SocksMethod>) -> Self {
+        self.methods = methods;
+        self
+    }
+
+    /// Checks username and password when the username/password method is negotiated.
+    pub fn with_credentials(mut self, credentials: Option<Arc<dyn CredentialChecker>>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Commands clients are allowed to request, others are refused.
+    pub fn with_commands(mut self, commands: Vec<SocksCmd>) -> Self {
+        self.commands = commands;
+        self
+    }
+
     pub fn state(&self) -> HandshakeState {
         self.state.clone()
     }
 
-    pub fn advance(&mut self, buf: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+    /// Name of the user the client authenticated as, if any.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Builds the reply telling the client why its last message was rejected.
+    ///
+    /// Returns `None` when there is no way to reply in the current state, e.g. when the
+    /// client does not speak a version we know.
+    pub fn error_reply(&self, err: &HandshakeError) -> Option<Vec<u8>> {
+        match &self.state {
+            HandshakeState::Auth(_, _) => Some(user_pass_reply(UserPassStatus::Failure)),
+            HandshakeState::Wait(version, _) => {
+                let unspecified = SocksAddr::Ipv4(Ipv4Addr::UNSPECIFIED);
+                Some(SocksReply::new(*version, err.into(), unspecified, 0).to_reply())
+            }
+            _ => None,
+        }
+    }
+
+    /// Parses one message from the start of `buf`.
+    ///
+    /// Returns the reply for the client together with the number of bytes the message took,
+    /// anything past that belongs to the next message. `HandshakeError::Incomplete` means
+    /// the message is not complete yet, the state is left untouched and the caller should
+    /// try again once more data arrives.
+    pub fn advance(&mut self, buf: &[u8]) -> Result<(Vec<u8>, usize), HandshakeError> {
         if buf.is_empty() {
             return Err(HandshakeError::Incomplete);
         }
 
-        match self.state {
-            HandshakeState::Init => self.advance_from_init(buf),
-            HandshakeState::Wait(current_version, _) => {
-                self.advance_from_wait(buf, current_version)
-            }
-            HandshakeState::Finished(_) => Ok(vec![]),
+        match &self.state {
+            HandshakeState::Init => match buf[0].try_into()? {
+                SocksVersion::V4 => self.advance_from_init_v4(buf),
+                SocksVersion::V5 => self.advance_from_init(buf),
+            },
+            HandshakeState::Auth(current_version, method) => match method {
+                SocksMethod::UsernamePassword => {
+                    let current_version = *current_version;
+                    self.advance_from_auth(buf, current_version)
+                }
+                _ => Err(HandshakeError::UnsupportedMethod),
+            },
+            HandshakeState::Wait(current_version, method) => match method {
+                SocksMethod::NoAuth | SocksMethod::UsernamePassword => {
+                    let current_version = *current_version;
+                    self.advance_from_wait(buf, current_version)
+                }
+                SocksMethod::NoAcceptableMethod => Err(HandshakeError::NoAcceptableMethod),
+            },
+            HandshakeState::Finished(_) | HandshakeState::Rejected(_) => Ok((vec![], 0)),
+        }
+    }
+
+    fn advance_from_auth(
+        &mut self,
+        buf: &[u8],
+        current_version: SocksVersion,
+    ) -> Result<(Vec<u8>, usize), HandshakeError> {
+        let (auth, consumed) = UserPassAuth::parse(buf)?;
+        let authenticated = self
+            .credentials
+            .as_ref()
+            .is_some_and(|creds| creds.check(&auth.username, &auth.password));
+
+        if authenticated {
+            self.user = Some(auth.username);
+            self.state = HandshakeState::Wait(current_version, SocksMethod::UsernamePassword);
+            Ok((user_pass_reply(UserPassStatus::Success), consumed))
+        } else {
+            self.state = HandshakeState::Rejected(HandshakeError::AuthFailed);
+            Ok((user_pass_reply(UserPassStatus::Failure), consumed))
         }
     }
 
@@ -110,7 +228,7 @@ impl HandshakeStateBuilder {
         &mut self,
         buf: &[u8],
         current_version: SocksVersion,
-    ) -> Result<Vec<u8>, HandshakeError> {
+    ) -> Result<(Vec<u8>, usize), HandshakeError> {
         let mut incoming = Cursor::new(buf);
         let version: SocksVersion = incoming.get_u8().try_into()?;
 
@@ -132,30 +250,60 @@ impl HandshakeStateBuilder {
         }
 
         let atyp: AddrType = incoming.get_u8().try_into()?;
-
-        if incoming.remaining() < 4 {
-            return Err(HandshakeError::Incomplete);
-        }
-
-        let addr = Ipv4Addr::from(incoming.get_u32());
+        let addr = SocksAddr::parse(atyp, &mut incoming)?;
 
         if incoming.remaining() < 2 {
             return Err(HandshakeError::Incomplete);
         }
 
         let port = incoming.get_u16();
+        if !self.commands.contains(&cmd) {
+            return Err(HandshakeError::CommandNotAllowed);
+        }
         self.state = HandshakeState::Finished(SocksHandshake {
             version,
             cmd,
             addr,
             port,
-            atyp,
         });
 
-        Ok(vec![])
+        Ok((vec![], incoming.position() as usize))
     }
 
-    fn advance_from_init(&mut self, buf: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+    /// SOCKS4 has no method negotiation, the first message is the request itself.
+    fn advance_from_init_v4(&mut self, buf: &[u8]) -> Result<(Vec<u8>, usize), HandshakeError> {
+        let (req, consumed) = match Socks4Request::parse(buf) {
+            Ok(parsed) => parsed,
+            Err(HandshakeError::Incomplete) => return Err(HandshakeError::Incomplete),
+            Err(err) => return Ok((self.reject_v4(err), buf.len())),
+        };
+
+        // USERID is only an identification, there is no way to authenticate with SOCKS4
+        if !self.methods.contains(&SocksMethod::NoAuth) {
+            return Ok((self.reject_v4(HandshakeError::NoAcceptableMethod), consumed));
+        }
+        if !self.commands.contains(&req.cmd) {
+            return Ok((self.reject_v4(HandshakeError::CommandNotAllowed), consumed));
+        }
+
+        self.state = HandshakeState::Finished(SocksHandshake {
+            version: SocksVersion::V4,
+            cmd: req.cmd,
+            addr: req.addr,
+            port: req.port,
+        });
+
+        Ok((vec![], consumed))
+    }
+
+    fn reject_v4(&mut self, err: HandshakeError) -> Vec<u8> {
+        let unspecified = SocksAddr::Ipv4(Ipv4Addr::UNSPECIFIED);
+        let reply = SocksReply::new(SocksVersion::V4, (&err).into(), unspecified, 0);
+        self.state = HandshakeState::Rejected(err);
+        reply.to_reply()
+    }
+
+    fn advance_from_init(&mut self, buf: &[u8]) -> Result<(Vec<u8>, usize), HandshakeError> {
         let mut incoming = Cursor::new(buf);
         let version: SocksVersion = incoming.get_u8().try_into()?;
 
@@ -176,18 +324,27 @@ impl HandshakeStateBuilder {
             methods.push(method);
         }
 
-        // should we check we read everything from buffeer?
-        // add selected method to state?
-        let reply = vec![version.into(), self.select_method(&methods).into()];
-        self.state = HandshakeState::Wait(version, methods);
+        let method = self.select_method(&methods);
+        let reply = vec![version.into(), method.into()];
+        self.state = match method {
+            SocksMethod::NoAuth => HandshakeState::Wait(version, method),
+            SocksMethod::UsernamePassword => HandshakeState::Auth(version, method),
+            // RFC 1928 requires the client to close the connection, we do not rely on it
+            SocksMethod::NoAcceptableMethod => {
+                HandshakeState::Rejected(HandshakeError::NoAcceptableMethod)
+            }
+        };
 
-        Ok(reply)
+        Ok((reply, incoming.position() as usize))
     }
 
-    fn select_method(&self, methods: &Vec<SocksMethod>) -> SocksMethod {
-        if methods.contains(&SocksMethod::NoAuth) {
-            return SocksMethod::NoAuth;
-        }
-        SocksMethod::NoAcceptableMethod
+    fn select_method(&self, offered: &[SocksMethod]) -> SocksMethod {
+        self.methods
+            .iter()
+            .copied()
+            // username/password cannot be used without something to check the credentials
+            .filter(|method| *method != SocksMethod::UsernamePassword || self.credentials.is_some())
+            .find(|method| offered.contains(method))
+            .unwrap_or(SocksMethod::NoAcceptableMethod)
     }
 }
@@ -0,0 +1,126 @@
+use std::{
+    fmt,
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use bytes::Buf;
+
+use super::{addr_type::AddrType, error::HandshakeError};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SocksAddr {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    Domain(String),
+}
+
+impl SocksAddr {
+    pub fn atyp(&self) -> AddrType {
+        match self {
+            Self::Ipv4(_) => AddrType::Ipv4,
+            Self::Ipv6(_) => AddrType::Ipv6,
+            Self::Domain(_) => AddrType::DomainName,
+        }
+    }
+
+    /// Returns the IP address, or `None` if the address still needs to be resolved.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Ipv4(addr) => Some(IpAddr::V4(*addr)),
+            Self::Ipv6(addr) => Some(IpAddr::V6(*addr)),
+            Self::Domain(_) => None,
+        }
+    }
+
+    /// Whether the address can be sent, domain names have to be 1 to 255 bytes long
+    /// for their length to fit into a single byte on the wire.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Self::Domain(domain) => !domain.is_empty() && domain.len() <= u8::MAX as usize,
+            _ => true,
+        }
+    }
+
+    /// Serializes the address without ATYP; domain names are prefixed with their length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Ipv4(addr) => addr.octets().to_vec(),
+            Self::Ipv6(addr) => addr.octets().to_vec(),
+            Self::Domain(domain) => {
+                let mut bytes = vec![domain.len() as u8];
+                bytes.extend_from_slice(domain.as_bytes());
+                bytes
+            }
+        }
+    }
+
+    pub fn parse(atyp: AddrType, incoming: &mut Cursor<&[u8]>) -> Result<Self, HandshakeError> {
+        match atyp {
+            AddrType::Ipv4 => {
+                if incoming.remaining() < 4 {
+                    return Err(HandshakeError::Incomplete);
+                }
+                Ok(Self::Ipv4(Ipv4Addr::from(incoming.get_u32())))
+            }
+            AddrType::Ipv6 => {
+                if incoming.remaining() < 16 {
+                    return Err(HandshakeError::Incomplete);
+                }
+                Ok(Self::Ipv6(Ipv6Addr::from(incoming.get_u128())))
+            }
+            AddrType::DomainName => {
+                if !incoming.has_remaining() {
+                    return Err(HandshakeError::Incomplete);
+                }
+                let len = incoming.get_u8() as usize;
+                if len == 0 {
+                    return Err(HandshakeError::InvalidDomainName);
+                }
+                if incoming.remaining() < len {
+                    return Err(HandshakeError::Incomplete);
+                }
+                let mut domain = vec![0_u8; len];
+                incoming.copy_to_slice(&mut domain);
+                let domain =
+                    String::from_utf8(domain).map_err(|_| HandshakeError::InvalidDomainName)?;
+                Ok(Self::Domain(domain))
+            }
+        }
+    }
+}
+
+impl FromStr for SocksAddr {
+    type Err = HandshakeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<IpAddr>() {
+            return Ok(addr.into());
+        }
+        let addr = Self::Domain(s.to_string());
+        if !addr.is_valid() {
+            return Err(HandshakeError::InvalidDomainName);
+        }
+        Ok(addr)
+    }
+}
+
+impl From<IpAddr> for SocksAddr {
+    fn from(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(addr) => Self::Ipv4(addr),
+            IpAddr::V6(addr) => Self::Ipv6(addr),
+        }
+    }
+}
+
+impl fmt::Display for SocksAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ipv4(addr) => write!(f, "{}", addr),
+            Self::Ipv6(addr) => write!(f, "{}", addr),
+            Self::Domain(domain) => write!(f, "{}", domain),
+        }
+    }
+}
@@ -3,6 +3,8 @@ use crate::handshake::error::HandshakeError;
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum AddrType {
     Ipv4,
+    DomainName,
+    Ipv6,
 }
 
 impl TryFrom<u8> for AddrType {
@@ -11,6 +13,8 @@ impl TryFrom<u8> for AddrType {
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             1 => Ok(Self::Ipv4),
+            3 => Ok(Self::DomainName),
+            4 => Ok(Self::Ipv6),
             _ => Err(Self::Error::UnsupportedAddrType),
         }
     }
@@ -20,6 +24,8 @@ impl From<AddrType> for u8 {
     fn from(method: AddrType) -> Self {
         match method {
             AddrType::Ipv4 => 1,
+            AddrType::DomainName => 3,
+            AddrType::Ipv6 => 4,
         }
     }
 }
@@ -1,8 +1,13 @@
+use serde::Deserialize;
+
 use crate::handshake::error::HandshakeError;
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SocksCmd {
     Connect,
+    Bind,
+    UdpAssociate,
 }
 
 impl TryFrom<u8> for SocksCmd {
@@ -11,6 +16,8 @@ impl TryFrom<u8> for SocksCmd {
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             1 => Ok(Self::Connect),
+            2 => Ok(Self::Bind),
+            3 => Ok(Self::UdpAssociate),
             _ => Err(Self::Error::UnsupportedCommand),
         }
     }
@@ -20,6 +27,8 @@ impl From<SocksCmd> for u8 {
     fn from(item: SocksCmd) -> Self {
         match item {
             SocksCmd::Connect => 1,
+            SocksCmd::Bind => 2,
+            SocksCmd::UdpAssociate => 3,
         }
     }
 }
@@ -1,6 +1,6 @@
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum HandshakeError {
     #[error("unsupported SOCKS version")]
     UnsupportedVersion,
@@ -11,14 +11,32 @@ pub enum HandshakeError {
     #[error("unsupported method")]
     UnsupportedMethod,
 
+    #[error("no acceptable method offered")]
+    NoAcceptableMethod,
+
     #[error("unsupported command")]
     UnsupportedCommand,
 
+    #[error("command not allowed")]
+    CommandNotAllowed,
+
     #[error("unsupported atyp")]
     UnsupportedAddrType,
 
+    #[error("invalid domain name")]
+    InvalidDomainName,
+
+    #[error("field too long")]
+    FieldTooLong,
+
     #[error("unsupported rep")]
     UnsupportedRepType,
+
+    #[error("unsupported username/password auth version")]
+    UnsupportedAuthVersion,
+
+    #[error("authentication failed")]
+    AuthFailed,
 }
 
 #[derive(Error, Debug)]
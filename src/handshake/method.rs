@@ -1,6 +1,7 @@
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum SocksMethod {
     NoAuth,
+    UsernamePassword,
     NoAcceptableMethod,
 }
 
@@ -8,6 +9,7 @@ impl From<u8> for SocksMethod {
     fn from(value: u8) -> Self {
         match value {
             0x00 => Self::NoAuth,
+            0x02 => Self::UsernamePassword,
             _ => Self::NoAcceptableMethod,
         }
     }
@@ -17,6 +19,7 @@ impl From<SocksMethod> for u8 {
     fn from(method: SocksMethod) -> Self {
         match method {
             SocksMethod::NoAuth => 0x00,
+            SocksMethod::UsernamePassword => 0x02,
             SocksMethod::NoAcceptableMethod => 0xFF,
         }
     }
@@ -3,47 +3,59 @@ use std::{io::Cursor, net::Ipv4Addr};
 use bytes::Buf;
 
 use super::{
-    addr_type::AddrType, error::HandshakeError, reply_field::ReplyField, version::SocksVersion,
-    WithIpv4Addr, WithPort,
+    addr::SocksAddr, addr_type::AddrType, error::HandshakeError, reply_field::ReplyField,
+    version::SocksVersion, WithAddr, WithPort,
 };
 
 #[derive(Debug)]
 pub struct SocksReply {
     pub version: SocksVersion,
     pub rep: ReplyField,
-    pub atyp: AddrType,
-    pub bnd_addr: Ipv4Addr,
+    pub bnd_addr: SocksAddr,
     pub bnd_port: u16,
 }
 
 impl SocksReply {
-    pub fn new(
-        version: SocksVersion,
-        rep: ReplyField,
-        atyp: AddrType,
-        addr: Ipv4Addr,
-        port: u16,
-    ) -> Self {
+    pub fn new(version: SocksVersion, rep: ReplyField, addr: SocksAddr, port: u16) -> Self {
         Self {
             version,
             rep,
-            atyp,
             bnd_addr: addr,
             bnd_port: port,
         }
     }
 
     pub fn to_reply(&self) -> Vec<u8> {
-        let mut reply: Vec<u8> = vec![];
-        reply.push(self.version.into());
-        reply.push(self.rep.into());
-        reply.push(0); // reserved
-        reply.push(self.atyp.into());
+        if self.version == SocksVersion::V4 {
+            return self.to_socks4_reply();
+        }
+
+        let mut reply: Vec<u8> = vec![
+            self.version.into(),
+            self.rep.into(),
+            0, // reserved
+            self.atyp().into(),
+        ];
         reply.append(&mut self.addr_to_bytes());
         reply.append(&mut self.port_to_bytes());
         reply
     }
 
+    fn to_socks4_reply(&self) -> Vec<u8> {
+        // SOCKS4 can only carry IPv4, zeros tell the client to use the proxy address instead
+        let addr = match &self.bnd_addr {
+            SocksAddr::Ipv4(addr) => *addr,
+            _ => Ipv4Addr::UNSPECIFIED,
+        };
+        let mut reply: Vec<u8> = vec![
+            0, // reply version
+            self.rep.to_socks4(),
+        ];
+        reply.append(&mut self.port_to_bytes());
+        reply.extend_from_slice(&addr.octets());
+        reply
+    }
+
     pub fn parse(buf: &[u8]) -> Result<Self, HandshakeError> {
         let mut incoming = Cursor::new(buf);
         let version: SocksVersion = incoming.get_u8().try_into()?;
@@ -62,24 +74,20 @@ impl SocksReply {
             return Err(HandshakeError::Incomplete);
         }
         let atyp: AddrType = incoming.get_u8().try_into()?;
-
-        if incoming.remaining() < 4 {
-            return Err(HandshakeError::Incomplete);
-        }
-        let addr = Ipv4Addr::from(incoming.get_u32());
+        let addr = SocksAddr::parse(atyp, &mut incoming)?;
 
         if incoming.remaining() < 2 {
             return Err(HandshakeError::Incomplete);
         }
         let port = incoming.get_u16();
 
-        Ok(Self::new(version, rep, atyp, addr, port))
+        Ok(Self::new(version, rep, addr, port))
     }
 }
 
-impl WithIpv4Addr for SocksReply {
-    fn addr(&self) -> Ipv4Addr {
-        self.bnd_addr
+impl WithAddr for SocksReply {
+    fn addr(&self) -> &SocksAddr {
+        &self.bnd_addr
     }
 }
 impl WithPort for SocksReply {
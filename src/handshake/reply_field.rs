@@ -1,3 +1,5 @@
+use std::io::{self, ErrorKind};
+
 use super::error::HandshakeError;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -13,6 +15,16 @@ pub enum ReplyField {
     AddrTypeNotSupported,
 }
 
+impl ReplyField {
+    /// SOCKS4 only tells the client whether the request was granted or rejected.
+    pub fn to_socks4(self) -> u8 {
+        match self {
+            Self::Succeeded => 90,
+            _ => 91,
+        }
+    }
+}
+
 impl TryFrom<u8> for ReplyField {
     type Error = HandshakeError;
 
@@ -48,3 +60,31 @@ impl From<ReplyField> for u8 {
         }
     }
 }
+
+impl From<&HandshakeError> for ReplyField {
+    fn from(err: &HandshakeError) -> Self {
+        match err {
+            HandshakeError::UnsupportedCommand => Self::CommandNotSupported,
+            HandshakeError::UnsupportedAddrType => Self::AddrTypeNotSupported,
+            HandshakeError::NoAcceptableMethod
+            | HandshakeError::AuthFailed
+            | HandshakeError::CommandNotAllowed => Self::ConnectionNotAllowed,
+            _ => Self::SocksServerFailure,
+        }
+    }
+}
+
+impl From<&io::Error> for ReplyField {
+    fn from(err: &io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::ConnectionRefused => Self::ConnectionRefused,
+            ErrorKind::HostUnreachable | ErrorKind::NotFound | ErrorKind::AddrNotAvailable => {
+                Self::HostUnreachable
+            }
+            ErrorKind::NetworkUnreachable | ErrorKind::NetworkDown => Self::NetworkUnreachable,
+            ErrorKind::TimedOut => Self::TtlExpired,
+            ErrorKind::PermissionDenied => Self::ConnectionNotAllowed,
+            _ => Self::SocksServerFailure,
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::{io::Cursor, net::Ipv4Addr};
+
+use bytes::Buf;
+
+use super::{addr::SocksAddr, cmd::SocksCmd, error::HandshakeError, version::SocksVersion};
+
+/// Longest USERID or hostname we accept, the protocol itself does not limit them.
+const MAX_FIELD_LEN: usize = 255;
+
+/// SOCKS4 request, including the SOCKS4a extension carrying a hostname.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Socks4Request {
+    pub cmd: SocksCmd,
+    pub addr: SocksAddr,
+    pub port: u16,
+    pub user_id: String,
+}
+
+impl Socks4Request {
+    pub fn to_request(&self) -> Vec<u8> {
+        let mut req: Vec<u8> = vec![SocksVersion::V4.into(), self.cmd.into()];
+        req.extend_from_slice(&self.port.to_be_bytes());
+        match &self.addr {
+            SocksAddr::Ipv4(addr) => req.extend_from_slice(&addr.octets()),
+            // 0.0.0.x with non-zero x tells the server a hostname follows the USERID
+            _ => req.extend_from_slice(&[0, 0, 0, 1]),
+        }
+        req.extend_from_slice(self.user_id.as_bytes());
+        req.push(0);
+        if let SocksAddr::Domain(domain) = &self.addr {
+            req.extend_from_slice(domain.as_bytes());
+            req.push(0);
+        }
+        req
+    }
+
+    /// Parses the request, returning it with the number of bytes it took.
+    pub fn parse(buf: &[u8]) -> Result<(Self, usize), HandshakeError> {
+        let mut incoming = Cursor::new(buf);
+        if incoming.remaining() < 8 {
+            return Err(HandshakeError::Incomplete);
+        }
+
+        let version: SocksVersion = incoming.get_u8().try_into()?;
+        if version != SocksVersion::V4 {
+            return Err(HandshakeError::UnsupportedVersion);
+        }
+        let cmd = match incoming.get_u8().try_into()? {
+            SocksCmd::UdpAssociate => return Err(HandshakeError::UnsupportedCommand),
+            cmd => cmd,
+        };
+        let port = incoming.get_u16();
+        let ip = Ipv4Addr::from(incoming.get_u32());
+        let user_id = String::from_utf8_lossy(&read_null_terminated(&mut incoming)?).into_owned();
+
+        let [a, b, c, d] = ip.octets();
+        let addr = if a == 0 && b == 0 && c == 0 && d != 0 {
+            let domain = read_null_terminated(&mut incoming)?;
+            if domain.is_empty() {
+                return Err(HandshakeError::InvalidDomainName);
+            }
+            let domain =
+                String::from_utf8(domain).map_err(|_| HandshakeError::InvalidDomainName)?;
+            SocksAddr::Domain(domain)
+        } else {
+            SocksAddr::Ipv4(ip)
+        };
+
+        let req = Self {
+            cmd,
+            addr,
+            port,
+            user_id,
+        };
+        Ok((req, incoming.position() as usize))
+    }
+}
+
+fn read_null_terminated(incoming: &mut Cursor<&[u8]>) -> Result<Vec<u8>, HandshakeError> {
+    let chunk = incoming.chunk();
+    match chunk.iter().position(|b| *b == 0) {
+        Some(len) if len <= MAX_FIELD_LEN => {
+            let field = chunk[..len].to_vec();
+            incoming.advance(len + 1);
+            Ok(field)
+        }
+        Some(_) => Err(HandshakeError::FieldTooLong),
+        None if chunk.len() > MAX_FIELD_LEN => Err(HandshakeError::FieldTooLong),
+        None => Err(HandshakeError::Incomplete),
+    }
+}
@@ -0,0 +1,66 @@
+use std::io::Cursor;
+
+use bytes::Buf;
+
+use super::{addr::SocksAddr, addr_type::AddrType, error::HandshakeError, WithAddr, WithPort};
+
+/// Header prepended to every datagram relayed through a UDP association.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub frag: u8,
+    pub addr: SocksAddr,
+    pub port: u16,
+}
+
+impl UdpHeader {
+    pub fn new(addr: SocksAddr, port: u16) -> Self {
+        Self {
+            frag: 0,
+            addr,
+            port,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header: Vec<u8> = vec![
+            0, // reserved
+            0, // reserved
+            self.frag,
+            self.atyp().into(),
+        ];
+        header.append(&mut self.addr_to_bytes());
+        header.append(&mut self.port_to_bytes());
+        header
+    }
+
+    /// Parses the header at the start of a datagram, returning it with the length of the header.
+    pub fn parse(buf: &[u8]) -> Result<(Self, usize), HandshakeError> {
+        let mut incoming = Cursor::new(buf);
+
+        if incoming.remaining() < 4 {
+            return Err(HandshakeError::Incomplete);
+        }
+        let _rsv = incoming.get_u16();
+        let frag = incoming.get_u8();
+        let atyp: AddrType = incoming.get_u8().try_into()?;
+        let addr = SocksAddr::parse(atyp, &mut incoming)?;
+
+        if incoming.remaining() < 2 {
+            return Err(HandshakeError::Incomplete);
+        }
+        let port = incoming.get_u16();
+
+        Ok((Self { frag, addr, port }, incoming.position() as usize))
+    }
+}
+
+impl WithAddr for UdpHeader {
+    fn addr(&self) -> &SocksAddr {
+        &self.addr
+    }
+}
+impl WithPort for UdpHeader {
+    fn port(&self) -> u16 {
+        self.port
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::Cursor;
+
+use bytes::Buf;
+
+use super::error::HandshakeError;
+
+/// Version of the username/password sub-negotiation, see RFC 1929.
+pub const USER_PASS_VERSION: u8 = 0x01;
+
+/// Whether a username or password can be sent, it has to fit into a single length byte.
+pub fn is_valid_field(field: &str) -> bool {
+    !field.is_empty() && field.len() <= u8::MAX as usize
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UserPassStatus {
+    Success,
+    Failure,
+}
+
+impl From<UserPassStatus> for u8 {
+    fn from(status: UserPassStatus) -> Self {
+        match status {
+            UserPassStatus::Success => 0x00,
+            UserPassStatus::Failure => 0x01,
+        }
+    }
+}
+
+impl From<u8> for UserPassStatus {
+    fn from(value: u8) -> Self {
+        // any non-zero status means failure
+        match value {
+            0x00 => Self::Success,
+            _ => Self::Failure,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct UserPassAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for UserPassAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserPassAuth")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+impl UserPassAuth {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    pub fn to_request(&self) -> Vec<u8> {
+        let mut req = vec![USER_PASS_VERSION, self.username.len() as u8];
+        req.extend_from_slice(self.username.as_bytes());
+        req.push(self.password.len() as u8);
+        req.extend_from_slice(self.password.as_bytes());
+        req
+    }
+
+    /// Parses the sub-negotiation request, returning it with the number of bytes it took.
+    pub fn parse(buf: &[u8]) -> Result<(Self, usize), HandshakeError> {
+        let mut incoming = Cursor::new(buf);
+        if !incoming.has_remaining() {
+            return Err(HandshakeError::Incomplete);
+        }
+        if incoming.get_u8() != USER_PASS_VERSION {
+            return Err(HandshakeError::UnsupportedAuthVersion);
+        }
+
+        let username = Self::parse_field(&mut incoming)?;
+        let password = Self::parse_field(&mut incoming)?;
+
+        Ok((Self { username, password }, incoming.position() as usize))
+    }
+
+    fn parse_field(incoming: &mut Cursor<&[u8]>) -> Result<String, HandshakeError> {
+        if !incoming.has_remaining() {
+            return Err(HandshakeError::Incomplete);
+        }
+        let len = incoming.get_u8() as usize;
+        if incoming.remaining() < len {
+            return Err(HandshakeError::Incomplete);
+        }
+        let mut field = vec![0_u8; len];
+        incoming.copy_to_slice(&mut field);
+        Ok(String::from_utf8_lossy(&field).into_owned())
+    }
+}
+
+pub fn user_pass_reply(status: UserPassStatus) -> Vec<u8> {
+    vec![USER_PASS_VERSION, status.into()]
+}
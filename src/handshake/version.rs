@@ -2,6 +2,7 @@ use crate::handshake::error::HandshakeError;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum SocksVersion {
+    V4,
     V5,
 }
 
@@ -10,6 +11,7 @@ impl TryFrom<u8> for SocksVersion {
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
+            4 => Ok(Self::V4),
             5 => Ok(Self::V5),
             _ => Err(Self::Error::UnsupportedVersion),
         }
@@ -19,6 +21,7 @@ impl TryFrom<u8> for SocksVersion {
 impl From<SocksVersion> for u8 {
     fn from(ver: SocksVersion) -> Self {
         match ver {
+            SocksVersion::V4 => 4,
             SocksVersion::V5 => 5,
         }
     }
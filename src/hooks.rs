@@ -0,0 +1,36 @@
+use std::{error::Error, net::SocketAddr};
+
+use crate::handshake::{addr::SocksAddr, cmd::SocksCmd};
+
+/// Request a client got through with, passed to hooks once it is established.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub client: SocketAddr,
+    /// Authenticated user, if the client authenticated.
+    pub user: Option<String>,
+    pub cmd: SocksCmd,
+    pub addr: SocksAddr,
+    pub port: u16,
+}
+
+/// Callbacks for embedders to observe and filter connections.
+///
+/// Hooks are called from the connection tasks and must not block.
+pub trait Hooks: Send + Sync {
+    /// Called for every accepted connection, returning `false` closes it right away.
+    fn on_accept(&self, _client: SocketAddr) -> bool {
+        true
+    }
+
+    /// Called once the request succeeded, before anything is relayed.
+    fn on_established(&self, _conn: &ConnectionInfo) {}
+
+    /// Called when a connection ends, with the error that ended it, if any.
+    fn on_closed(&self, _client: SocketAddr, _err: Option<&dyn Error>) {}
+}
+
+/// Hooks doing nothing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoHooks;
+
+impl Hooks for NoHooks {}
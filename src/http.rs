@@ -0,0 +1,194 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use thiserror::Error;
+
+use crate::handshake::{addr::SocksAddr, reply_field::ReplyField};
+
+/// Longest header we accept, in requests of HTTP proxy clients and responses of upstream proxies.
+pub const MAX_HEADER_LEN: usize = 8192;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum HttpError {
+    #[error("incomplete input")]
+    Incomplete,
+
+    #[error("request header is too long")]
+    HeaderTooLong,
+
+    #[error("malformed request")]
+    Malformed,
+
+    #[error("unsupported request target {0:?}")]
+    UnsupportedTarget(String),
+}
+
+/// Headers meant for the proxy alone, never forwarded to the origin server.
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Head of a request sent to the proxy, everything up to the empty line.
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    /// Parses the request head, returns it along with its length in bytes.
+    pub fn parse(buf: &[u8]) -> Result<(Self, usize), HttpError> {
+        let head_len = match buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None if buf.len() >= MAX_HEADER_LEN => return Err(HttpError::HeaderTooLong),
+            None => return Err(HttpError::Incomplete),
+        };
+        if head_len > MAX_HEADER_LEN {
+            return Err(HttpError::HeaderTooLong);
+        }
+        let head = std::str::from_utf8(&buf[..head_len - 4]).map_err(|_| HttpError::Malformed)?;
+        let mut lines = head.split("\r\n");
+
+        let request_line = lines.next().ok_or(HttpError::Malformed)?;
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version))
+                if parts.next().is_none() && version.starts_with("HTTP/1.") =>
+            {
+                (method, target, version)
+            }
+            _ => return Err(HttpError::Malformed),
+        };
+
+        let headers = lines
+            .map(|line| {
+                line.split_once(':')
+                    .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                    .ok_or(HttpError::Malformed)
+            })
+            .collect::<Result<_, _>>()?;
+
+        let req = Self {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers,
+        };
+        Ok((req, head_len))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Username and password from a Basic `Proxy-Authorization` header.
+    pub fn basic_auth(&self) -> Option<(String, String)> {
+        let value = self.header("Proxy-Authorization")?;
+        let (scheme, token) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = BASE64.decode(token.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some((username.to_string(), password.to_string()))
+    }
+
+    /// Splits an absolute `http://` target into the host to connect to and the origin-form path.
+    pub fn absolute_target(&self) -> Result<(SocksAddr, u16, String), HttpError> {
+        let unsupported = || HttpError::UnsupportedTarget(self.target.clone());
+        let rest = self
+            .target
+            .get(..7)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+            .map(|_| &self.target[7..])
+            .ok_or_else(unsupported)?;
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+        let has_port = authority
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.contains(']'));
+        let (addr, port) = if has_port {
+            parse_authority(authority)
+        } else {
+            parse_authority(&format!("{}:80", authority))
+        }
+        .ok_or_else(unsupported)?;
+        Ok((addr, port, path.to_string()))
+    }
+
+    /// Request head to send to the origin server for a forwarded request.
+    ///
+    /// The target is rewritten to origin-form and hop-by-hop headers are removed, including
+    /// those the `Connection` header names (RFC 9110, section 7.6.1).
+    /// The connection is closed after a single request, so that we do not have to track
+    /// where following requests on it are going.
+    pub fn to_origin_request(&self, path: &str) -> Vec<u8> {
+        let connection_options: Vec<&str> = self
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        let mut head = format!("{} {} {}\r\n", self.method, path, self.version);
+        for (name, value) in self.headers.iter() {
+            let hop_by_hop = HOP_BY_HOP_HEADERS
+                .iter()
+                .chain(connection_options.iter())
+                .any(|header| name.eq_ignore_ascii_case(header));
+            if !hop_by_hop {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        head.into_bytes()
+    }
+}
+
+/// Parses `host:port`, IPv6 addresses are written in brackets.
+pub fn parse_authority(authority: &str) -> Option<(SocksAddr, u16)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']')?,
+        None => host,
+    };
+    let addr = host.parse().ok()?;
+    let port = port.parse().ok()?;
+    Some((addr, port))
+}
+
+/// Response with an empty body, the connection is closed after sending it.
+pub fn error_response(status: u16, reason: &str) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {} {}\r\n", status, reason);
+    if status == 407 {
+        response.push_str("Proxy-Authenticate: Basic realm=\"shoes\"\r\n");
+    }
+    response.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+    response.into_bytes()
+}
+
+/// Status telling the client why the target could not be reached.
+pub fn status_for(rep: ReplyField) -> (u16, &'static str) {
+    match rep {
+        ReplyField::ConnectionNotAllowed => (403, "Forbidden"),
+        ReplyField::TtlExpired => (504, "Gateway Timeout"),
+        _ => (502, "Bad Gateway"),
+    }
+}
+
+pub fn connect_response() -> Vec<u8> {
+    b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec()
+}
@@ -1,7 +1,16 @@
+pub mod acl;
+pub mod auth;
 pub mod cli;
 pub mod client;
+pub mod config;
+pub mod dialer;
 pub mod handshake;
+pub mod hooks;
+pub mod http;
+pub mod relay;
+pub mod resolver;
 pub mod server;
+pub mod upstream;
 
 // should we use 'anyhow' instead of boxing errors?
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
@@ -0,0 +1,431 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::mpsc,
+    time::{self, Instant},
+};
+use tracing::debug;
+
+use crate::{
+    dialer::Dialer,
+    handshake::{addr::SocksAddr, udp::UdpHeader},
+    resolver::{resolve, Resolver, SystemResolver},
+};
+
+/// Stream to a target host, either a plain connection or one tunneled through proxies.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + Sync> AsyncStream for S {}
+
+pub type BoxStream = Box<dyn AsyncStream>;
+
+/// Relays data between the client and the target until both directions are done.
+///
+/// When one side closes its write half, the shutdown is propagated to the other side
+/// while the opposite direction keeps flowing. Errors on either side end the relay,
+/// so does nothing being read from either side for `idle_timeout`, with a `TimedOut` error.
+pub async fn relay_tcp<C, T>(
+    client: &mut C,
+    target: &mut T,
+    idle_timeout: Option<Duration>,
+) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    T: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let activity = Activity::new();
+    let mut client = Tracked::new(client, &activity);
+    let mut target = Tracked::new(target, &activity);
+
+    let (to_target, to_client) = tokio::select! {
+        res = copy_bidirectional(&mut client, &mut target) => res?,
+        _ = activity.idle(idle_timeout) => {
+            return Err(io::Error::new(ErrorKind::TimedOut, "relayed connection is idle"));
+        }
+    };
+    debug!(
+        "Relay finished, {} bytes sent to target, {} bytes sent to client",
+        to_target, to_client
+    );
+    Ok((to_target, to_client))
+}
+
+/// Time of the last read on either side of a relay.
+struct Activity {
+    start: Instant,
+    // milliseconds since start, atomic so that the relay future stays Send
+    last_read: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_read: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last_read.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn last_read(&self) -> Instant {
+        self.start + Duration::from_millis(self.last_read.load(Ordering::Relaxed))
+    }
+
+    /// Completes once nothing was read for `timeout`, never if there is no timeout.
+    async fn idle(&self, timeout: Option<Duration>) {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return std::future::pending().await,
+        };
+        loop {
+            let deadline = self.last_read() + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            time::sleep_until(deadline).await;
+        }
+    }
+}
+
+/// Stream wrapper recording reads in the shared activity.
+struct Tracked<'a, S: ?Sized> {
+    inner: &'a mut S,
+    activity: &'a Activity,
+}
+
+impl<'a, S: ?Sized> Tracked<'a, S> {
+    fn new(inner: &'a mut S, activity: &'a Activity) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<S: AsyncRead + Unpin + ?Sized> AsyncRead for Tracked<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.activity.touch();
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin + ?Sized> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Largest datagram we are able to relay.
+const UDP_BUF_SIZE: usize = 65535;
+/// Datagrams of an association waiting for their target to be resolved, more are dropped.
+const MAX_PENDING_LOOKUPS: usize = 64;
+
+/// Decides whether a datagram may be sent to the target, given as requested and resolved.
+pub type TargetFilter = Box<dyn Fn(&SocksAddr, SocketAddr) -> bool + Send + Sync>;
+
+/// Datagram whose target domain name was looked up outside the relay loop.
+struct Lookup {
+    addr: SocksAddr,
+    targets: io::Result<Vec<SocketAddr>>,
+    payload: Vec<u8>,
+}
+
+/// Relay for a single UDP association.
+///
+/// Datagrams from the client are stripped of their SOCKS header and sent to the target,
+/// datagrams from targets get the header prepended and are sent back to the client.
+pub struct UdpRelay {
+    client_socket: UdpSocket,
+    outbound_v4: Option<UdpSocket>,
+    outbound_v6: Option<UdpSocket>,
+    client_ip: IpAddr,
+    // port the client announced in its request, 0 if it does not know it yet
+    client_port: u16,
+    filter: Option<TargetFilter>,
+    resolver: Arc<dyn Resolver>,
+    idle_timeout: Option<Duration>,
+}
+
+impl fmt::Debug for UdpRelay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UdpRelay")
+            .field("client_socket", &self.client_socket)
+            .field("client_ip", &self.client_ip)
+            .field("client_port", &self.client_port)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UdpRelay {
+    /// Binds the client facing socket on `local_ip` and has the dialer bind sockets
+    /// for outbound traffic.
+    ///
+    /// Only datagrams coming from `client` are relayed, a zero port accepts any port.
+    pub async fn bind(
+        local_ip: IpAddr,
+        client: SocketAddr,
+        dialer: &dyn Dialer,
+        user: Option<&str>,
+    ) -> io::Result<Self> {
+        let client_socket = UdpSocket::bind(SocketAddr::new(local_ip.to_canonical(), 0)).await?;
+        // either family may not be available, targets of that family are unreachable then
+        let outbound_v4 = dialer.bind_udp(false, user).await;
+        let outbound_v6 = dialer.bind_udp(true, user).await.ok();
+        let outbound_v4 = match (outbound_v4, &outbound_v6) {
+            (Ok(socket), _) => Some(socket),
+            (Err(_), Some(_)) => None,
+            (Err(err), None) => return Err(err),
+        };
+
+        Ok(Self {
+            client_socket,
+            outbound_v4,
+            outbound_v6,
+            client_ip: client.ip().to_canonical(),
+            client_port: client.port(),
+            filter: None,
+            resolver: Arc::new(SystemResolver),
+            idle_timeout: None,
+        })
+    }
+
+    /// Drops datagrams for targets the filter does not allow.
+    pub fn with_filter(
+        mut self,
+        filter: impl Fn(&SocksAddr, SocketAddr) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Resolver for domain names datagrams are addressed to.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Ends the association when no datagram was relayed in either direction for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.client_socket.local_addr()
+    }
+
+    /// Relays datagrams until the controlling TCP connection is closed or the association
+    /// is idle for longer than its idle timeout.
+    pub async fn run<C>(&self, control: &mut C) -> io::Result<()>
+    where
+        C: AsyncRead + Unpin + ?Sized,
+    {
+        let mut control_buf = [0_u8; 64];
+        let mut client_buf = vec![0_u8; UDP_BUF_SIZE];
+        let mut v4_buf = vec![0_u8; UDP_BUF_SIZE];
+        let mut v6_buf = vec![0_u8; UDP_BUF_SIZE];
+        let mut client_addr: Option<SocketAddr> = None;
+        let mut last_datagram = Instant::now();
+        // slow lookups must not hold up datagrams to other targets or back to the client
+        let (lookups, mut resolved) = mpsc::channel(MAX_PENDING_LOOKUPS);
+
+        loop {
+            let idle_deadline = self.idle_timeout.map(|timeout| last_datagram + timeout);
+            tokio::select! {
+                _ = sleep_until_opt(idle_deadline) => {
+                    debug!("UDP association is idle, ending it");
+                    return Ok(());
+                }
+                res = control.read(&mut control_buf) => {
+                    match res {
+                        Ok(0) => {
+                            debug!("Control connection closed, ending UDP association");
+                            return Ok(());
+                        }
+                        // the client is not supposed to send anything, just ignore it
+                        Ok(_) => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+                res = self.client_socket.recv_from(&mut client_buf) => {
+                    let (n_read, from) = res?;
+                    if !self.is_client(from) {
+                        debug!("Dropping datagram from unexpected source {}", from);
+                        continue;
+                    }
+                    client_addr = Some(from);
+                    last_datagram = Instant::now();
+                    if let Err(err) = self.send_to_target(&client_buf[..n_read], &lookups).await {
+                        debug!("Failed to relay datagram to target: {}", err);
+                    }
+                }
+                Some(lookup) = resolved.recv() => {
+                    let res = match lookup.targets {
+                        Ok(targets) => self.send_resolved(&lookup.addr, targets, &lookup.payload).await,
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = res {
+                        debug!("Failed to relay datagram to target: {}", err);
+                    }
+                }
+                res = recv_from_opt(self.outbound_v4.as_ref(), &mut v4_buf) => {
+                    let (n_read, from) = res?;
+                    last_datagram = Instant::now();
+                    self.send_to_client(client_addr, from, &v4_buf[..n_read]).await?;
+                }
+                res = recv_from_opt(self.outbound_v6.as_ref(), &mut v6_buf) => {
+                    let (n_read, from) = res?;
+                    last_datagram = Instant::now();
+                    self.send_to_client(client_addr, from, &v6_buf[..n_read]).await?;
+                }
+            }
+        }
+    }
+
+    fn is_client(&self, from: SocketAddr) -> bool {
+        from.ip().to_canonical() == self.client_ip
+            && (self.client_port == 0 || from.port() == self.client_port)
+    }
+
+    async fn send_to_target(
+        &self,
+        datagram: &[u8],
+        lookups: &mpsc::Sender<Lookup>,
+    ) -> io::Result<()> {
+        let (header, header_len) = UdpHeader::parse(datagram)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        // fragmentation is optional and we do not implement it
+        if header.frag != 0 {
+            debug!("Dropping fragmented datagram");
+            return Ok(());
+        }
+        let payload = &datagram[header_len..];
+
+        let needs_lookup = match &header.addr {
+            SocksAddr::Domain(domain) => domain.parse::<IpAddr>().is_err(),
+            _ => false,
+        };
+        if !needs_lookup {
+            let targets = resolve(&*self.resolver, &header.addr, header.port).await?;
+            return self.send_resolved(&header.addr, targets, payload).await;
+        }
+
+        let permit = lookups.clone().try_reserve_owned().map_err(|_| {
+            io::Error::new(
+                ErrorKind::WouldBlock,
+                "too many datagrams waiting for lookups",
+            )
+        })?;
+        let resolver = self.resolver.clone();
+        let payload = payload.to_vec();
+        tokio::spawn(async move {
+            let targets = resolve(&*resolver, &header.addr, header.port).await;
+            permit.send(Lookup {
+                addr: header.addr,
+                targets,
+                payload,
+            });
+        });
+        Ok(())
+    }
+
+    async fn send_resolved(
+        &self,
+        addr: &SocksAddr,
+        targets: Vec<SocketAddr>,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let target = targets
+            .into_iter()
+            .filter(|target| self.outbound_for(target).is_some())
+            .find(|target| {
+                self.filter
+                    .as_ref()
+                    .is_none_or(|allowed| allowed(addr, *target))
+            })
+            .ok_or_else(|| io::Error::new(ErrorKind::AddrNotAvailable, "no usable address"))?;
+
+        let socket = self
+            .outbound_for(&target)
+            .expect("target family is filtered");
+        socket.send_to(payload, target).await?;
+        Ok(())
+    }
+
+    fn outbound_for(&self, target: &SocketAddr) -> Option<&UdpSocket> {
+        match target {
+            SocketAddr::V4(_) => self.outbound_v4.as_ref(),
+            SocketAddr::V6(_) => self.outbound_v6.as_ref(),
+        }
+    }
+
+    async fn send_to_client(
+        &self,
+        client_addr: Option<SocketAddr>,
+        from: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let client_addr = match client_addr {
+            Some(addr) => addr,
+            // we cannot get here before the client sent something, just to be sure
+            None => return Ok(()),
+        };
+
+        let header = UdpHeader::new(from.ip().to_canonical().into(), from.port());
+        let mut datagram = header.to_bytes();
+        datagram.extend_from_slice(payload);
+        self.client_socket.send_to(&datagram, client_addr).await?;
+        Ok(())
+    }
+}
+
+async fn recv_from_opt(
+    socket: Option<&UdpSocket>,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn sleep_until_opt(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
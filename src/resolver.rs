@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use tokio::net::lookup_host;
+use tracing::debug;
+
+pub mod dns;
+pub mod message;
+
+use crate::dialer::BoxFuture;
+use crate::handshake::addr::SocksAddr;
+
+/// Looks up the addresses of domain names requested by clients.
+pub trait Resolver: Send + Sync {
+    /// Addresses of the domain name, an empty list if it has none.
+    fn lookup<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>>;
+}
+
+/// Resolver of the operating system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            let addrs = lookup_host((name, 0)).await?;
+            Ok(addrs.map(|addr| addr.ip()).collect())
+        })
+    }
+}
+
+/// Answers names from a static map, like /etc/hosts, and asks the inner resolver for the rest.
+pub struct HostsResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    inner: Arc<dyn Resolver>,
+}
+
+impl HostsResolver {
+    pub fn new(hosts: HashMap<String, Vec<IpAddr>>, inner: Arc<dyn Resolver>) -> Self {
+        let hosts = hosts
+            .into_iter()
+            .map(|(name, ips)| (normalize(&name), ips))
+            .collect();
+        Self { hosts, inner }
+    }
+}
+
+impl Resolver for HostsResolver {
+    fn lookup<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        match self.hosts.get(&normalize(name)) {
+            Some(ips) => {
+                let ips = ips.clone();
+                Box::pin(async move { Ok(ips) })
+            }
+            None => self.inner.lookup(name),
+        }
+    }
+}
+
+/// Names compare case insensitively, with or without the trailing dot.
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Turns a SOCKS address into socket addresses, looking domain names up with the resolver.
+pub async fn resolve(
+    resolver: &dyn Resolver,
+    addr: &SocksAddr,
+    port: u16,
+) -> io::Result<Vec<SocketAddr>> {
+    let domain = match addr {
+        SocksAddr::Ipv4(addr) => return Ok(vec![SocketAddr::from((*addr, port))]),
+        SocksAddr::Ipv6(addr) => return Ok(vec![SocketAddr::from((*addr, port))]),
+        SocksAddr::Domain(domain) => domain,
+    };
+    // clients sometimes send IP addresses as domain names
+    if let Ok(ip) = domain.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    debug!("Resolving domain {:?}", domain);
+    let ips = resolver.lookup(domain).await?;
+    if ips.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("no addresses found for {}", domain),
+        ));
+    }
+    Ok(ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
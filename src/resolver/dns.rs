@@ -0,0 +1,453 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::{self, Instant},
+};
+use tracing::debug;
+
+use super::{
+    message::{build_query, parse_response, MessageError, Response, ResponseCode},
+    message::{TYPE_A, TYPE_AAAA},
+    Resolver,
+};
+use crate::dialer::BoxFuture;
+
+pub const DNS_PORT: u16 = 53;
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(30);
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/// Longest time an answer is cached, whatever TTL the server gave it.
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Servers do not send more over UDP unless we offer a larger size with EDNS, which we do not.
+const MAX_UDP_RESPONSE_LEN: usize = 512;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid name server {0:?}, expected e.g. 1.1.1.1, [::1]:53 or tcp://9.9.9.9")]
+pub struct InvalidNameServer(String);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Queries go over UDP, truncated answers are repeated over TCP.
+    Udp,
+    Tcp,
+}
+
+/// Upstream DNS server, written as `[udp://|tcp://]ip[:port]` with IPv6 addresses in
+/// brackets when a port is given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NameServer {
+    pub addr: SocketAddr,
+    pub transport: Transport,
+}
+
+impl FromStr for NameServer {
+    type Err = InvalidNameServer;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (transport, addr) = match s.split_once("://") {
+            Some(("udp", addr)) => (Transport::Udp, addr),
+            Some(("tcp", addr)) => (Transport::Tcp, addr),
+            Some(_) => return Err(InvalidNameServer(s.to_string())),
+            None => (Transport::Udp, s),
+        };
+        let addr = match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => addr
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, DNS_PORT))
+                .map_err(|_| InvalidNameServer(s.to_string()))?,
+        };
+        Ok(Self { addr, transport })
+    }
+}
+
+impl fmt::Display for NameServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.transport {
+            Transport::Udp => write!(f, "udp://{}", self.addr),
+            Transport::Tcp => write!(f, "tcp://{}", self.addr),
+        }
+    }
+}
+
+/// Resolver asking the given name servers directly, caching their answers.
+///
+/// Servers are asked in order, the next one is asked when one fails or does not answer
+/// within the query timeout. Answers are cached for their TTL, names without addresses
+/// for the TTL the authority gives for missing answers, at most the negative TTL.
+pub struct DnsResolver {
+    servers: Vec<NameServer>,
+    query_timeout: Duration,
+    negative_ttl: Duration,
+    cache: Mutex<Cache>,
+}
+
+impl fmt::Debug for DnsResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsResolver")
+            .field("servers", &self.servers)
+            .field("query_timeout", &self.query_timeout)
+            .field("negative_ttl", &self.negative_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DnsResolver {
+    pub fn new(servers: Vec<NameServer>) -> Self {
+        Self {
+            servers,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+            cache: Mutex::new(Cache::new(DEFAULT_CACHE_SIZE)),
+        }
+    }
+
+    /// Time a single server gets to answer a query.
+    pub fn with_query_timeout(mut self, timeout: Duration) -> Self {
+        self.query_timeout = timeout;
+        self
+    }
+
+    /// Longest time names without addresses are cached.
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Answers kept in the cache, zero disables caching.
+    pub fn with_cache_size(mut self, size: usize) -> Self {
+        self.cache = Mutex::new(Cache::new(size));
+        self
+    }
+
+    async fn lookup_type(&self, name: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+        if let Some(ips) = self.cache.lock().unwrap().get(name, qtype) {
+            debug!("Found {:?} in the DNS cache", name);
+            return Ok(ips);
+        }
+
+        let response = self.query(name, qtype).await?;
+        let (ips, ttl) = if response.records.is_empty() {
+            let ttl = response
+                .negative_ttl
+                .map(|ttl| Duration::from_secs(ttl.into()))
+                .unwrap_or(self.negative_ttl)
+                .min(self.negative_ttl);
+            (Vec::new(), ttl)
+        } else {
+            let ttl = response.records.iter().map(|(_, ttl)| *ttl).min();
+            let ips = response.records.into_iter().map(|(ip, _)| ip).collect();
+            (ips, Duration::from_secs(ttl.unwrap_or(0).into()))
+        };
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(name, qtype, ips.clone(), ttl.min(MAX_TTL));
+        Ok(ips)
+    }
+
+    async fn query(&self, name: &str, qtype: u16) -> io::Result<Response> {
+        let id = query_id();
+        let query = build_query(id, name, qtype)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+
+        let mut last_err = None;
+        for server in self.servers.iter() {
+            let response = time::timeout(
+                self.query_timeout,
+                exchange(server, &query, id, name, qtype),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("name server {} did not answer in time", server),
+                ))
+            });
+            match response {
+                Ok(response)
+                    if matches!(
+                        response.rcode,
+                        ResponseCode::NoError | ResponseCode::NameError
+                    ) =>
+                {
+                    return Ok(response)
+                }
+                Ok(response) => {
+                    debug!(
+                        "Name server {} answered {:?} with {:?}",
+                        server, name, response.rcode
+                    );
+                    last_err = Some(io::Error::other(format!(
+                        "name server {} failed to resolve {}",
+                        server, name
+                    )));
+                }
+                Err(err) => {
+                    debug!("Failed to ask name server {}: {}", server, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(ErrorKind::NotFound, "no name servers configured")))
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn lookup<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            let (ipv6, ipv4) = tokio::join!(
+                self.lookup_type(&name, TYPE_AAAA),
+                self.lookup_type(&name, TYPE_A)
+            );
+
+            let mut ips = Vec::new();
+            let mut last_err = None;
+            for res in [ipv6, ipv4] {
+                match res {
+                    Ok(found) => ips.extend(found),
+                    Err(err) => last_err = Some(err),
+                }
+            }
+            match last_err {
+                Some(err) if ips.is_empty() => Err(err),
+                _ => Ok(ips),
+            }
+        })
+    }
+}
+
+async fn exchange(
+    server: &NameServer,
+    query: &[u8],
+    id: u16,
+    name: &str,
+    qtype: u16,
+) -> io::Result<Response> {
+    match server.transport {
+        Transport::Udp => {
+            let response = exchange_udp(server.addr, query, id, name, qtype).await?;
+            if !response.truncated {
+                return Ok(response);
+            }
+            debug!("Answer from {} is truncated, asking again over TCP", server);
+            exchange_tcp(server.addr, query, id, name, qtype).await
+        }
+        Transport::Tcp => exchange_tcp(server.addr, query, id, name, qtype).await,
+    }
+}
+
+async fn exchange_udp(
+    addr: SocketAddr,
+    query: &[u8],
+    id: u16,
+    name: &str,
+    qtype: u16,
+) -> io::Result<Response> {
+    let local_ip: IpAddr = match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((local_ip, 0)).await?;
+    socket.connect(addr).await?;
+    socket.send(query).await?;
+
+    let mut buf = vec![0_u8; MAX_UDP_RESPONSE_LEN];
+    loop {
+        let n_read = socket.recv(&mut buf).await?;
+        match parse_response(&buf[..n_read], id, name, qtype) {
+            // someone else's answer or a late one to an earlier query
+            Err(MessageError::Mismatched) => continue,
+            res => return res.map_err(invalid_data),
+        }
+    }
+}
+
+async fn exchange_tcp(
+    addr: SocketAddr,
+    query: &[u8],
+    id: u16,
+    name: &str,
+    qtype: u16,
+) -> io::Result<Response> {
+    let mut stream = TcpStream::connect(addr).await?;
+    // over TCP every message is prefixed with its length
+    let mut request = (query.len() as u16).to_be_bytes().to_vec();
+    request.extend_from_slice(query);
+    stream.write_all(&request).await?;
+
+    let len = stream.read_u16().await? as usize;
+    let mut buf = vec![0_u8; len];
+    stream.read_exact(&mut buf).await?;
+    parse_response(&buf, id, name, qtype).map_err(invalid_data)
+}
+
+fn invalid_data(err: MessageError) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+/// Unpredictable query id, so that off-path answers are hard to forge.
+fn query_id() -> u16 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    // every RandomState is seeded randomly, no need for a dependency on a random generator
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish() as u16
+}
+
+/// Answers by name and record type, an empty answer caches a name without addresses.
+struct Cache {
+    entries: HashMap<(String, u16), CacheEntry>,
+    capacity: usize,
+}
+
+struct CacheEntry {
+    ips: Vec<IpAddr>,
+    expires: Instant,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    fn get(&mut self, name: &str, qtype: u16) -> Option<Vec<IpAddr>> {
+        let key = (name.to_string(), qtype);
+        let entry = self.entries.get(&key)?;
+        if entry.expires <= Instant::now() {
+            self.entries.remove(&key);
+            return None;
+        }
+        Some(entry.ips.clone())
+    }
+
+    fn insert(&mut self, name: &str, qtype: u16, ips: Vec<IpAddr>, ttl: Duration) {
+        if self.capacity == 0 || ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        if self.entries.len() >= self.capacity {
+            self.entries.retain(|_, entry| entry.expires > now);
+        }
+        if self.entries.len() >= self.capacity {
+            // make room by dropping the entry that would expire first anyway
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = oldest {
+                self.entries.remove(&key);
+            }
+        }
+        let entry = CacheEntry {
+            ips,
+            expires: now + ttl,
+        };
+        self.entries.insert((name.to_string(), qtype), entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(ip: &str) -> Vec<IpAddr> {
+        vec![ip.parse().unwrap()]
+    }
+
+    #[test]
+    fn cached_answers_expire() {
+        let mut cache = Cache::new(8);
+        cache.insert(
+            "a.test",
+            TYPE_A,
+            ips("192.0.2.1"),
+            Duration::from_millis(20),
+        );
+        cache.insert("nx.test", TYPE_A, vec![], Duration::from_secs(60));
+        assert_eq!(cache.get("a.test", TYPE_A), Some(ips("192.0.2.1")));
+        assert_eq!(cache.get("a.test", TYPE_AAAA), None);
+        assert_eq!(cache.get("nx.test", TYPE_A), Some(vec![]));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(cache.get("a.test", TYPE_A), None);
+        assert_eq!(cache.get("nx.test", TYPE_A), Some(vec![]));
+    }
+
+    #[test]
+    fn full_cache_drops_expired_answers_first() {
+        let mut cache = Cache::new(2);
+        cache.insert(
+            "a.test",
+            TYPE_A,
+            ips("192.0.2.1"),
+            Duration::from_millis(10),
+        );
+        cache.insert("b.test", TYPE_A, ips("192.0.2.2"), Duration::from_secs(60));
+        std::thread::sleep(Duration::from_millis(20));
+        cache.insert("c.test", TYPE_A, ips("192.0.2.3"), Duration::from_secs(60));
+
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.get("b.test", TYPE_A), Some(ips("192.0.2.2")));
+        assert_eq!(cache.get("c.test", TYPE_A), Some(ips("192.0.2.3")));
+    }
+
+    #[test]
+    fn full_cache_evicts_answer_expiring_first() {
+        let mut cache = Cache::new(2);
+        cache.insert("a.test", TYPE_A, ips("192.0.2.1"), Duration::from_secs(60));
+        cache.insert("b.test", TYPE_A, ips("192.0.2.2"), Duration::from_secs(10));
+        cache.insert("c.test", TYPE_A, ips("192.0.2.3"), Duration::from_secs(30));
+
+        assert_eq!(cache.get("a.test", TYPE_A), Some(ips("192.0.2.1")));
+        assert_eq!(cache.get("b.test", TYPE_A), None);
+        assert_eq!(cache.get("c.test", TYPE_A), Some(ips("192.0.2.3")));
+    }
+
+    #[test]
+    fn nothing_is_cached_without_capacity_or_ttl() {
+        let mut cache = Cache::new(0);
+        cache.insert("a.test", TYPE_A, ips("192.0.2.1"), Duration::from_secs(60));
+        assert_eq!(cache.get("a.test", TYPE_A), None);
+
+        let mut cache = Cache::new(8);
+        cache.insert("a.test", TYPE_A, ips("192.0.2.1"), Duration::ZERO);
+        assert_eq!(cache.get("a.test", TYPE_A), None);
+    }
+
+    #[test]
+    fn parses_name_servers() {
+        let server: NameServer = "1.1.1.1".parse().unwrap();
+        assert_eq!(server.addr, "1.1.1.1:53".parse().unwrap());
+        assert_eq!(server.transport, Transport::Udp);
+
+        let server: NameServer = "tcp://[::1]:5353".parse().unwrap();
+        assert_eq!(server.addr, "[::1]:5353".parse().unwrap());
+        assert_eq!(server.transport, Transport::Tcp);
+
+        assert!("dns.example".parse::<NameServer>().is_err());
+        assert!("tls://1.1.1.1".parse::<NameServer>().is_err());
+    }
+}
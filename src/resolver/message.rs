@@ -0,0 +1,461 @@
+use std::{
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use bytes::Buf;
+use thiserror::Error;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const TYPE_CNAME: u16 = 5;
+const TYPE_SOA: u16 = 6;
+const CLASS_IN: u16 = 1;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+
+/// Longest domain name in wire format, including the length bytes.
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum MessageError {
+    #[error("invalid domain name {0:?}")]
+    InvalidName(String),
+
+    #[error("incomplete message")]
+    Incomplete,
+
+    #[error("message is not a response to our query")]
+    Mismatched,
+
+    #[error("name compression loops")]
+    CompressionLoop,
+}
+
+/// Response codes we act upon, everything else is treated as a server failure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseCode {
+    NoError,
+    NameError,
+    Other(u8),
+}
+
+impl From<u8> for ResponseCode {
+    fn from(rcode: u8) -> Self {
+        match rcode {
+            0 => Self::NoError,
+            3 => Self::NameError,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// Parts of a response to an A or AAAA query the resolver cares about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub rcode: ResponseCode,
+    /// The answer did not fit into a UDP datagram, the query has to be repeated over TCP.
+    pub truncated: bool,
+    /// Addresses of the queried type along with their TTL in seconds.
+    pub records: Vec<(IpAddr, u32)>,
+    /// Seconds a missing answer may be cached for, taken from the SOA record of the authority.
+    pub negative_ttl: Option<u32>,
+}
+
+/// Builds a recursive query for records of `qtype` of the domain name.
+pub fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, MessageError> {
+    let invalid = || MessageError::InvalidName(name.to_string());
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // one question, no answer, authority or additional records
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    let name_start = query.len();
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(invalid());
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    if query.len() - name_start > MAX_NAME_LEN {
+        return Err(invalid());
+    }
+
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Parses the response to the query with the given id, name and type.
+///
+/// Only addresses owned by the queried name, or by a name its CNAME chain leads to, are
+/// returned, anything else in the answer section is not ours to believe.
+pub fn parse_response(
+    buf: &[u8],
+    id: u16,
+    name: &str,
+    qtype: u16,
+) -> Result<Response, MessageError> {
+    let mut incoming = Cursor::new(buf);
+    if incoming.remaining() < 12 {
+        return Err(MessageError::Incomplete);
+    }
+    let response_id = incoming.get_u16();
+    let flags = incoming.get_u16();
+    if response_id != id || flags & FLAG_RESPONSE == 0 {
+        return Err(MessageError::Mismatched);
+    }
+    let qdcount = incoming.get_u16();
+    let ancount = incoming.get_u16();
+    let nscount = incoming.get_u16();
+    let _arcount = incoming.get_u16();
+
+    // the response has to repeat the one question we asked
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if qdcount != 1 {
+        return Err(MessageError::Mismatched);
+    }
+    let question = read_name(&mut incoming)?;
+    if incoming.remaining() < 4 {
+        return Err(MessageError::Incomplete);
+    }
+    let (question_type, question_class) = (incoming.get_u16(), incoming.get_u16());
+    if question != name || question_type != qtype || question_class != CLASS_IN {
+        return Err(MessageError::Mismatched);
+    }
+
+    let mut answers = Vec::with_capacity(ancount.into());
+    for _ in 0..ancount {
+        answers.push(read_record(&mut incoming)?);
+    }
+
+    // answers may start with a CNAME chain, the addresses it leads to follow it
+    let mut owners = vec![name];
+    while owners.len() <= answers.len() {
+        let owner = owners.last().expect("starts with the queried name");
+        let target = answers
+            .iter()
+            .find(|record| record.rtype == TYPE_CNAME && record.name == *owner)
+            .map(|record| read_name(&mut record.data_cursor(buf)))
+            .transpose()?;
+        match target {
+            Some(target) if !owners.contains(&target) => owners.push(target),
+            _ => break,
+        }
+    }
+
+    let mut records = Vec::new();
+    for record in answers.iter() {
+        if record.rtype != qtype || !owners.contains(&record.name) {
+            continue;
+        }
+        match (record.rtype, record.data.len()) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = record.data.try_into().expect("length is checked");
+                records.push((Ipv4Addr::from(octets).into(), record.ttl));
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = record.data.try_into().expect("length is checked");
+                records.push((Ipv6Addr::from(octets).into(), record.ttl));
+            }
+            _ => {}
+        }
+    }
+
+    // RFC 2308: negative answers are cached for the shorter of the SOA TTL and its MINIMUM
+    let mut negative_ttl = None;
+    for _ in 0..nscount {
+        let record = read_record(&mut incoming)?;
+        if record.rtype == TYPE_SOA && record.data.len() >= 4 {
+            let minimum =
+                u32::from_be_bytes(record.data[record.data.len() - 4..].try_into().unwrap());
+            negative_ttl = Some(record.ttl.min(minimum));
+        }
+    }
+
+    Ok(Response {
+        rcode: ResponseCode::from((flags & 0x000f) as u8),
+        truncated: flags & FLAG_TRUNCATED != 0,
+        records,
+        negative_ttl,
+    })
+}
+
+/// Resource record with its owner name in lowercase, without the trailing dot.
+struct Record<'a> {
+    name: String,
+    rtype: u16,
+    ttl: u32,
+    data: &'a [u8],
+    /// Position of the data in the message, names in it may point anywhere before it.
+    offset: usize,
+}
+
+impl<'a> Record<'a> {
+    fn data_cursor(&self, buf: &'a [u8]) -> Cursor<&'a [u8]> {
+        let mut cursor = Cursor::new(&buf[..self.offset + self.data.len()]);
+        cursor.set_position(self.offset as u64);
+        cursor
+    }
+}
+
+fn read_record<'a>(incoming: &mut Cursor<&'a [u8]>) -> Result<Record<'a>, MessageError> {
+    let name = read_name(incoming)?;
+    if incoming.remaining() < 10 {
+        return Err(MessageError::Incomplete);
+    }
+    let rtype = incoming.get_u16();
+    let _class = incoming.get_u16();
+    let ttl = incoming.get_u32();
+    let rdlen = incoming.get_u16() as usize;
+    if incoming.remaining() < rdlen {
+        return Err(MessageError::Incomplete);
+    }
+    let offset = incoming.position() as usize;
+    let data = &incoming.get_ref()[offset..offset + rdlen];
+    incoming.advance(rdlen);
+    Ok(Record {
+        name,
+        rtype,
+        ttl,
+        data,
+        offset,
+    })
+}
+
+/// Reads a possibly compressed domain name, in lowercase and without the trailing dot.
+fn read_name(incoming: &mut Cursor<&[u8]>) -> Result<String, MessageError> {
+    let buf = *incoming.get_ref();
+    let mut labels = Vec::new();
+    let mut name_len = 1;
+    // where the name continues after a pointer, the cursor stays behind the first one
+    let mut position = incoming.position() as usize;
+    let mut jumped = false;
+    loop {
+        let len = *buf.get(position).ok_or(MessageError::Incomplete)? as usize;
+        position += 1;
+        match len {
+            0 => break,
+            len if len & 0xc0 == 0xc0 => {
+                let low = *buf.get(position).ok_or(MessageError::Incomplete)? as usize;
+                if !jumped {
+                    incoming.set_position(position as u64 + 1);
+                    jumped = true;
+                }
+                // only pointing backwards guarantees that the pointers do not loop
+                let target = (len & 0x3f) << 8 | low;
+                if target >= position - 1 {
+                    return Err(MessageError::CompressionLoop);
+                }
+                position = target;
+            }
+            len => {
+                let label = buf
+                    .get(position..position + len)
+                    .ok_or(MessageError::Incomplete)?;
+                name_len += len + 1;
+                if name_len > MAX_NAME_LEN {
+                    return Err(MessageError::InvalidName(labels.join(".")));
+                }
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                position += len;
+            }
+        }
+    }
+    if !jumped {
+        incoming.set_position(position as u64);
+    }
+    Ok(labels.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0x1234;
+    const FLAGS_ANSWER: u16 = FLAG_RESPONSE | FLAG_RECURSION_DESIRED | 0x0080;
+    const FLAGS_NAME_ERROR: u16 = FLAGS_ANSWER | 3;
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut wire = Vec::new();
+        for label in name.split('.') {
+            wire.push(label.len() as u8);
+            wire.extend_from_slice(label.as_bytes());
+        }
+        wire.push(0);
+        wire
+    }
+
+    fn pointer(offset: usize) -> Vec<u8> {
+        (0xc000 | offset as u16).to_be_bytes().to_vec()
+    }
+
+    fn record(owner: &[u8], rtype: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut record = owner.to_vec();
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    /// Message asking the one question for `qname`, records are appended by the tests.
+    fn message(id: u16, flags: u16, qname: &str, qtype: u16, counts: (u16, u16)) -> Vec<u8> {
+        let mut msg = Vec::new();
+        for field in [id, flags, 1, counts.0, counts.1, 0] {
+            msg.extend_from_slice(&field.to_be_bytes());
+        }
+        msg.extend_from_slice(&name(qname));
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> Vec<u8> {
+        let mut data = name("ns.test");
+        data.extend_from_slice(&name("admin.test"));
+        for field in [1, 7200, 900, 1209600, minimum] {
+            data.extend_from_slice(&field.to_be_bytes());
+        }
+        record(&name("test"), TYPE_SOA, ttl, &data)
+    }
+
+    #[test]
+    fn parses_answer() {
+        let mut msg = message(ID, FLAGS_ANSWER, "a.test", TYPE_A, (1, 0));
+        msg.extend(record(&pointer(12), TYPE_A, 60, &[192, 0, 2, 1]));
+
+        let response = parse_response(&msg, ID, "A.test.", TYPE_A).unwrap();
+        assert_eq!(response.rcode, ResponseCode::NoError);
+        assert!(!response.truncated);
+        assert_eq!(response.records, vec![("192.0.2.1".parse().unwrap(), 60)]);
+        assert_eq!(response.negative_ttl, None);
+    }
+
+    #[test]
+    fn builds_query() {
+        let expected = message(ID, FLAG_RECURSION_DESIRED, "a.test", TYPE_AAAA, (0, 0));
+        assert_eq!(build_query(ID, "a.test.", TYPE_AAAA).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        for name in ["a..test", "", &"a".repeat(64), &"a.".repeat(128)] {
+            assert!(matches!(
+                build_query(ID, name, TYPE_A),
+                Err(MessageError::InvalidName(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_id() {
+        let msg = message(ID + 1, FLAGS_ANSWER, "a.test", TYPE_A, (0, 0));
+        assert_eq!(
+            parse_response(&msg, ID, "a.test", TYPE_A),
+            Err(MessageError::Mismatched)
+        );
+    }
+
+    #[test]
+    fn rejects_query() {
+        let msg = message(ID, FLAG_RECURSION_DESIRED, "a.test", TYPE_A, (0, 0));
+        assert_eq!(
+            parse_response(&msg, ID, "a.test", TYPE_A),
+            Err(MessageError::Mismatched)
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_question() {
+        let msg = message(ID, FLAGS_ANSWER, "b.test", TYPE_A, (0, 0));
+        assert_eq!(
+            parse_response(&msg, ID, "a.test", TYPE_A),
+            Err(MessageError::Mismatched)
+        );
+
+        let msg = message(ID, FLAGS_ANSWER, "a.test", TYPE_AAAA, (0, 0));
+        assert_eq!(
+            parse_response(&msg, ID, "a.test", TYPE_A),
+            Err(MessageError::Mismatched)
+        );
+    }
+
+    #[test]
+    fn rejects_forward_pointer() {
+        let mut msg = message(ID, FLAGS_ANSWER, "a.test", TYPE_A, (1, 0));
+        // the owner points at itself
+        let offset = msg.len();
+        msg.extend(record(&pointer(offset), TYPE_A, 60, &[192, 0, 2, 1]));
+        assert_eq!(
+            parse_response(&msg, ID, "a.test", TYPE_A),
+            Err(MessageError::CompressionLoop)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_message() {
+        let mut msg = message(ID, FLAGS_ANSWER, "a.test", TYPE_A, (1, 0));
+        msg.extend(record(&pointer(12), TYPE_A, 60, &[192, 0, 2, 1]));
+        msg.pop();
+        assert_eq!(
+            parse_response(&msg, ID, "a.test", TYPE_A),
+            Err(MessageError::Incomplete)
+        );
+    }
+
+    #[test]
+    fn follows_cname_chain() {
+        let mut msg = message(ID, FLAGS_ANSWER, "www.test", TYPE_A, (4, 0));
+        let cname_data = msg.len() + 12;
+        msg.extend(record(&pointer(12), TYPE_CNAME, 300, &name("cdn.test")));
+        msg.extend(record(&name("evil.test"), TYPE_A, 300, &[203, 0, 113, 66]));
+        msg.extend(record(&pointer(cname_data), TYPE_A, 30, &[192, 0, 2, 1]));
+        // an AAAA record of the chain is not what we asked for
+        msg.extend(record(&pointer(cname_data), TYPE_AAAA, 30, &[0; 16]));
+
+        let response = parse_response(&msg, ID, "www.test", TYPE_A).unwrap();
+        assert_eq!(response.records, vec![("192.0.2.1".parse().unwrap(), 30)]);
+    }
+
+    #[test]
+    fn ignores_records_of_other_names() {
+        let mut msg = message(ID, FLAGS_ANSWER, "a.test", TYPE_A, (1, 0));
+        msg.extend(record(&name("b.test"), TYPE_A, 60, &[203, 0, 113, 66]));
+
+        let response = parse_response(&msg, ID, "a.test", TYPE_A).unwrap();
+        assert!(response.records.is_empty());
+    }
+
+    #[test]
+    fn negative_ttl_is_shorter_of_soa_ttl_and_minimum() {
+        let mut msg = message(ID, FLAGS_NAME_ERROR, "nx.test", TYPE_A, (0, 1));
+        msg.extend(soa(3600, 10));
+        let response = parse_response(&msg, ID, "nx.test", TYPE_A).unwrap();
+        assert_eq!(response.rcode, ResponseCode::NameError);
+        assert_eq!(response.negative_ttl, Some(10));
+
+        let mut msg = message(ID, FLAGS_ANSWER, "nx.test", TYPE_A, (0, 1));
+        msg.extend(soa(5, 300));
+        let response = parse_response(&msg, ID, "nx.test", TYPE_A).unwrap();
+        assert_eq!(response.rcode, ResponseCode::NoError);
+        assert_eq!(response.negative_ttl, Some(5));
+    }
+
+    #[test]
+    fn reports_truncation() {
+        let msg = message(ID, FLAGS_ANSWER | FLAG_TRUNCATED, "a.test", TYPE_A, (0, 0));
+        assert!(
+            parse_response(&msg, ID, "a.test", TYPE_A)
+                .unwrap()
+                .truncated
+        );
+    }
+}
@@ -1,113 +1,632 @@
-use std::io::ErrorKind;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use crate::Result;
+use bytes::{Buf, BytesMut};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc, Notify, Semaphore},
+    task::{JoinError, JoinHandle, JoinSet},
+    time::{self, Instant},
 };
-use tracing::{debug, error};
+use tracing::{debug, error, info};
 
+use crate::acl::{AccessDenied, Acl, AclRequest, Action};
+use crate::auth::CredentialChecker;
+use crate::dialer::{DialError, Dialer, DirectDialer, Outbound, UpstreamDialer};
 use crate::handshake::{
-    reply::SocksReply, reply_field::ReplyField, HandshakeState, HandshakeStateBuilder,
+    cmd::SocksCmd, error::HandshakeError, method::SocksMethod, reply::SocksReply,
+    reply_field::ReplyField, version::SocksVersion, HandshakeState, HandshakeStateBuilder,
     SocksHandshake,
 };
+use crate::hooks::{ConnectionInfo, Hooks, NoHooks};
+use crate::http::{self, HttpError, HttpRequest};
+use crate::relay::{relay_tcp, BoxStream, UdpRelay};
+use crate::resolver::{resolve, Resolver, SystemResolver};
+use crate::upstream::Upstream;
 
-#[derive(Debug)]
-struct Server {
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Settings applied to every connection accepted by the server.
+#[derive(Clone)]
+pub struct Settings {
+    /// Methods offered to clients, in order of preference.
+    pub methods: Vec<SocksMethod>,
+    pub credentials: Option<Arc<dyn CredentialChecker>>,
+    /// Commands clients may use, others are answered with `ConnectionNotAllowed`.
+    pub commands: Vec<SocksCmd>,
+    pub acl: Acl,
+    pub timeouts: Timeouts,
+    /// Makes outbound connections and sockets.
+    pub dialer: Arc<dyn Dialer>,
+    /// Looks up domain names of targets, BIND peers and UDP datagrams.
+    pub resolver: Arc<dyn Resolver>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            methods: vec![SocksMethod::NoAuth],
+            credentials: None,
+            commands: vec![SocksCmd::Connect, SocksCmd::Bind, SocksCmd::UdpAssociate],
+            acl: Acl::default(),
+            timeouts: Timeouts::default(),
+            dialer: Arc::new(DirectDialer::new()),
+            resolver: Arc::new(SystemResolver),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Time the client gets to send its greeting, authenticate and send the request.
+    pub handshake: Duration,
+    /// Time to resolve and connect to the target, or to wait for the inbound BIND connection.
+    pub connect: Duration,
+    /// Relayed connections and UDP associations with no traffic for this long are closed.
+    pub idle: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: DEFAULT_HANDSHAKE_TIMEOUT,
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            idle: None,
+        }
+    }
+}
+
+/// Listening socket together with the settings for connections accepted on it.
+pub(crate) struct Listener {
     listener: TcpListener,
+    settings: Arc<Settings>,
 }
 
-impl Server {
-    async fn run(&mut self) -> Result<()> {
+impl Listener {
+    pub fn new(listener: TcpListener, settings: Settings) -> Self {
+        Self {
+            listener,
+            settings: Arc::new(settings),
+        }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the task running it is aborted.
+    ///
+    /// Every connection holds a clone of `shutdown_complete` until it ends
+    /// and is closed as soon as `notify_close` fires.
+    async fn run(
+        self,
+        limiter: Arc<ConnLimiter>,
+        hooks: Arc<dyn Hooks>,
+        notify_close: broadcast::Sender<()>,
+        shutdown_complete: mpsc::Sender<()>,
+    ) {
         loop {
-            let socket = self.accept().await?;
+            let (socket, client_addr) = self.accept().await;
+            let ip_permit = match limiter.try_acquire_ip(client_addr.ip()) {
+                Some(ip_permit) => ip_permit,
+                None => {
+                    debug!(
+                        "Closing connection from {}, too many connections from its address",
+                        client_addr
+                    );
+                    continue;
+                }
+            };
+            if !hooks.on_accept(client_addr) {
+                debug!("Closing connection from {}, rejected by hook", client_addr);
+                continue;
+            }
+            // slots are shared by all listeners, so only take one once there is a connection,
+            // while waiting for it further clients of this listener queue up in the backlog
+            let permit = limiter
+                .connections
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("connection semaphore is never closed");
 
-            let mut handler = ConnHandler::new(socket);
+            let mut handler =
+                ConnHandler::new(socket, client_addr, self.settings.clone(), hooks.clone());
+            let hooks = hooks.clone();
+            let mut close = notify_close.subscribe();
+            let shutdown_complete = shutdown_complete.clone();
 
             tokio::spawn(async move {
-                if let Err(err) = handler.run().await {
+                let res = tokio::select! {
+                    res = handler.run() => res,
+                    _ = close.recv() => {
+                        debug!("Closing connection from {} on shutdown", client_addr);
+                        Ok(())
+                    }
+                };
+                if let Err(err) = &res {
                     error!(err);
                 }
+                hooks.on_closed(client_addr, res.as_ref().err().map(|err| err.as_ref()));
+                drop(permit);
+                drop(ip_permit);
+                drop(shutdown_complete);
             });
         }
     }
 
-    async fn accept(&mut self) -> Result<TcpStream> {
-        match self.listener.accept().await {
-            Ok((socket, _)) => Ok(socket),
-            Err(err) => Err(err.into()),
+    /// Accepts the next connection, retrying with a backoff when accepting fails.
+    ///
+    /// Errors such as running out of file descriptors go away once other connections
+    /// are closed, so they must not take the server down.
+    async fn accept(&self) -> (TcpStream, SocketAddr) {
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+        loop {
+            match self.listener.accept().await {
+                Ok(conn) => return conn,
+                // the client went away before we accepted it, nothing wrong with the listener
+                Err(err) if is_connection_error(&err) => {
+                    debug!(cause = %err, "accepting connection failed");
+                }
+                Err(err) => {
+                    error!(cause = %err, "accepting connection failed, retrying in {:?}", backoff);
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                }
+            }
+        }
+    }
+}
+
+fn timed_out(msg: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::TimedOut, msg)
+}
+
+fn is_connection_error(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted
+    )
+}
+
+/// Limits on the number of connections open at the same time, shared by all listeners.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_connections: usize,
+    /// Connections a single client IP address may have open, unlimited if not set.
+    pub max_connections_per_ip: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_ip: None,
         }
     }
 }
 
-#[derive(Debug)]
+struct ConnLimiter {
+    connections: Arc<Semaphore>,
+    max_per_ip: Option<usize>,
+    // open connections per client address, addresses without any are removed
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnLimiter {
+    fn new(limits: Limits) -> Self {
+        Self {
+            connections: Arc::new(Semaphore::new(limits.max_connections)),
+            max_per_ip: limits.max_connections_per_ip,
+            per_ip: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn try_acquire_ip(self: &Arc<Self>, ip: IpAddr) -> Option<IpPermit> {
+        let ip = ip.to_canonical();
+        let mut per_ip = self.per_ip.lock().unwrap();
+        let open = per_ip.entry(ip).or_insert(0);
+        if self.max_per_ip.is_some_and(|max| *open >= max) {
+            return None;
+        }
+        *open += 1;
+        Some(IpPermit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// Counts a connection against the limit of its client address until dropped.
+struct IpPermit {
+    limiter: Arc<ConnLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut per_ip = self.limiter.per_ip.lock().unwrap();
+        if let Some(open) = per_ip.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+struct Server {
+    listeners: Vec<Listener>,
+    limits: Limits,
+    drain_timeout: Duration,
+    hooks: Arc<dyn Hooks>,
+}
+
+impl Server {
+    async fn run(self, shutdown: impl Future) -> std::result::Result<(), JoinError> {
+        let (notify_close, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel::<()>(1);
+
+        let limiter = Arc::new(ConnLimiter::new(self.limits));
+
+        let mut accept_loops = JoinSet::new();
+        for listener in self.listeners {
+            debug!("Accepting connections on {:?}", listener.local_addr());
+            accept_loops.spawn(listener.run(
+                limiter.clone(),
+                self.hooks.clone(),
+                notify_close.clone(),
+                shutdown_complete_tx.clone(),
+            ));
+        }
+
+        let res = tokio::select! {
+            res = wait_for_accept_loops(&mut accept_loops) => res,
+            _ = shutdown => {
+                info!("Shutting down, no longer accepting connections");
+                Ok(())
+            }
+        };
+
+        // stop accepting, then give open connections the time to finish on their own
+        accept_loops.shutdown().await;
+        drop(shutdown_complete_tx);
+        if time::timeout(self.drain_timeout, shutdown_complete_rx.recv())
+            .await
+            .is_err()
+        {
+            info!(
+                "Closing connections still open after {:?}",
+                self.drain_timeout
+            );
+            let _ = notify_close.send(());
+            shutdown_complete_rx.recv().await;
+        }
+        res
+    }
+}
+
+async fn wait_for_accept_loops(
+    accept_loops: &mut JoinSet<()>,
+) -> std::result::Result<(), JoinError> {
+    // accept loops only end if they panic, the first one takes the whole server down
+    while let Some(res) = accept_loops.join_next().await {
+        res?;
+    }
+    Ok(())
+}
+
 enum ConnState {
     Handshake,
-    ConnEstablished(TcpStream),
+    ConnEstablished(BoxStream),
+    UdpAssociated(UdpRelay),
+}
+
+impl std::fmt::Debug for ConnState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Handshake => write!(f, "Handshake"),
+            Self::ConnEstablished(_) => write!(f, "ConnEstablished"),
+            Self::UdpAssociated(relay) => f.debug_tuple("UdpAssociated").field(relay).finish(),
+        }
+    }
 }
 
-#[derive(Debug)]
 struct ConnHandler {
     socket: TcpStream,
+    client_addr: SocketAddr,
     conn_state: ConnState,
+    settings: Arc<Settings>,
+    hooks: Arc<dyn Hooks>,
+    // user the client authenticated as
+    user: Option<String>,
+    // request being handled, once it is read
+    request: Option<SocksHandshake>,
 }
 
 impl ConnHandler {
-    pub fn new(socket: TcpStream) -> Self {
+    pub fn new(
+        socket: TcpStream,
+        client_addr: SocketAddr,
+        settings: Arc<Settings>,
+        hooks: Arc<dyn Hooks>,
+    ) -> Self {
         Self {
             socket,
+            client_addr,
             conn_state: ConnState::Handshake,
+            settings,
+            hooks,
+            user: None,
+            request: None,
         }
     }
 
-    fn clear_buffer(&self, buf: &mut [u8; 1024]) {
-        buf.iter_mut().for_each(|m| *m = 0)
-    }
-
     #[tracing::instrument(skip(self))]
     async fn run(&mut self) -> Result<()> {
-        self.read_handshake().await
+        // the deadline only covers reading the handshake, not handling the request
+        let deadline = Instant::now() + self.settings.timeouts.handshake;
+        let early_data = if self.is_http(deadline).await? {
+            self.read_http_request(deadline).await?
+        } else {
+            self.read_handshake(deadline).await?
+        };
+        if let (Some(hs), false) = (
+            &self.request,
+            matches!(self.conn_state, ConnState::Handshake),
+        ) {
+            self.hooks.on_established(&ConnectionInfo {
+                client: self.client_addr,
+                user: self.user.clone(),
+                cmd: hs.cmd,
+                addr: hs.addr.clone(),
+                port: hs.port,
+            });
+        }
+
+        match &mut self.conn_state {
+            ConnState::ConnEstablished(target_socket) => {
+                if !early_data.is_empty() {
+                    debug!(
+                        "Sending {} bytes received with the handshake",
+                        early_data.len()
+                    );
+                    target_socket.write_all(&early_data).await?;
+                }
+                debug!("Relaying data between client and target host...");
+                let idle_timeout = self.settings.timeouts.idle;
+                match relay_tcp(&mut self.socket, target_socket, idle_timeout).await {
+                    Err(err) if err.kind() == ErrorKind::TimedOut => {
+                        debug!("Closing idle connection");
+                    }
+                    res => {
+                        res?;
+                    }
+                }
+            }
+            ConnState::UdpAssociated(udp_relay) => {
+                debug!("Relaying datagrams for UDP association...");
+                udp_relay.run(&mut self.socket).await?;
+            }
+            ConnState::Handshake => {}
+        }
+
+        Ok(())
     }
 
-    async fn read_handshake(&mut self) -> Result<()> {
-        let mut buf = [0_u8; 1024];
-        let mut hs_builder = HandshakeStateBuilder::new();
+    /// Reads and handles handshake messages until the request is processed.
+    ///
+    /// Returns bytes the client sent past the handshake, they belong to the relayed stream.
+    async fn read_handshake(&mut self, deadline: Instant) -> Result<BytesMut> {
+        let mut buf = BytesMut::with_capacity(1024);
+        let mut hs_builder = HandshakeStateBuilder::new()
+            .with_methods(self.settings.methods.clone())
+            .with_credentials(self.settings.credentials.clone())
+            .with_commands(self.settings.commands.clone());
 
-        loop {
-            let n_read = self.socket.read(&mut buf).await?;
+        while let ConnState::Handshake = self.conn_state {
+            if !buf.is_empty() {
+                match hs_builder.advance(&buf) {
+                    Ok((reply, consumed)) => {
+                        buf.advance(consumed);
+                        self.handle_hs_advance(&hs_builder, reply).await?;
+                        continue;
+                    }
+                    // wait for the rest of the message
+                    Err(HandshakeError::Incomplete) => {}
+                    Err(err) => {
+                        if let Some(reply) = hs_builder.error_reply(&err) {
+                            debug!("rejecting client with hs error reply: {:?}", reply);
+                            self.reply_to_client(reply).await?;
+                        }
+                        return Err(Box::new(err));
+                    }
+                }
+            }
+
+            let n_read = match time::timeout_at(deadline, self.socket.read_buf(&mut buf)).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    debug!("Client did not finish the handshake in time");
+                    return Err(Box::new(timed_out("handshake timed out")));
+                }
+            };
             if n_read == 0 {
                 break;
             }
-            let msg = format!(
-                "Accepted: {:?}, num of bytes read: {:?} in state: {:?}",
-                &buf[..n_read].to_vec(),
-                n_read,
-                self.conn_state
-            );
-            debug!(msg);
-
-            match &mut self.conn_state {
-                ConnState::Handshake => {
-                    match hs_builder.advance(&buf) {
-                        Ok(reply) => {
-                            // If advancing ends up in error, buffer is not cleared.
-                            // That could become a problem if we decide to support partial handshake
-                            self.handle_hs_advance(&hs_builder, reply).await?;
-                            self.clear_buffer(&mut buf);
-                        }
-                        Err(err) => return Err(Box::new(err)),
+            // the sub-negotiation carries the password, possibly sent along with the greeting
+            if matches!(
+                hs_builder.state(),
+                HandshakeState::Init | HandshakeState::Auth(_, _)
+            ) {
+                debug!("Accepted {} bytes in state: {:?}", n_read, self.conn_state);
+            } else {
+                let msg = format!(
+                    "Accepted: {:?}, num of bytes read: {:?} in state: {:?}",
+                    &buf[buf.len() - n_read..],
+                    n_read,
+                    self.conn_state
+                );
+                debug!(msg);
+            }
+        }
+
+        Ok(buf)
+    }
+
+    /// Tells HTTP proxy requests from SOCKS greetings by their first byte.
+    ///
+    /// SOCKS messages start with the protocol version, HTTP requests with the method name.
+    async fn is_http(&mut self, deadline: Instant) -> Result<bool> {
+        let mut first = [0_u8; 1];
+        let n_read = match time::timeout_at(deadline, self.socket.peek(&mut first)).await {
+            Ok(res) => res?,
+            Err(_) => {
+                debug!("Client did not send anything in time");
+                return Err(Box::new(timed_out("handshake timed out")));
+            }
+        };
+        Ok(n_read == 1 && first[0].is_ascii_uppercase())
+    }
+
+    /// Reads and handles an HTTP proxy request, either CONNECT or a request to forward.
+    ///
+    /// Returns bytes to send to the target first, the rewritten request when forwarding.
+    async fn read_http_request(&mut self, deadline: Instant) -> Result<BytesMut> {
+        let mut buf = BytesMut::with_capacity(1024);
+        let (req, head_len) = loop {
+            match HttpRequest::parse(&buf) {
+                Ok(parsed) => break parsed,
+                // wait for the rest of the header
+                Err(HttpError::Incomplete) => {}
+                Err(err) => {
+                    let (status, reason) = match err {
+                        HttpError::HeaderTooLong => (431, "Request Header Fields Too Large"),
+                        _ => (400, "Bad Request"),
                     };
+                    self.reply_to_client(http::error_response(status, reason))
+                        .await?;
+                    return Err(Box::new(err));
                 }
-                ConnState::ConnEstablished(target_socket) => {
-                    // improve debug - logging buffer of bytes is not very useful in real life
-                    // debug!("Writing buffer: {:?}", buf);
-                    debug!("Sending data to target host...");
-                    target_socket.write(&mut buf).await?;
+            }
+
+            let n_read = match time::timeout_at(deadline, self.socket.read_buf(&mut buf)).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    debug!("Client did not finish the HTTP request in time");
+                    return Err(Box::new(timed_out("handshake timed out")));
                 }
+            };
+            if n_read == 0 {
+                return Ok(buf);
+            }
+        };
+        buf.advance(head_len);
+        debug!("HTTP proxy request: {} {}", req.method, req.target);
+
+        match self.http_user(&req) {
+            Ok(user) => self.user = user,
+            Err(err) => {
+                debug!("Rejecting HTTP client, no valid credentials");
+                let response = http::error_response(407, "Proxy Authentication Required");
+                self.reply_to_client(response).await?;
+                return Err(Box::new(err));
             }
         }
 
-        Ok(())
+        let target = if req.method == "CONNECT" {
+            http::parse_authority(&req.target)
+                .map(|(addr, port)| (addr, port, None))
+                .ok_or_else(|| HttpError::UnsupportedTarget(req.target.clone()))
+        } else {
+            req.absolute_target()
+                .map(|(addr, port, path)| (addr, port, Some(req.to_origin_request(&path))))
+        };
+        let (addr, port, forwarded_head) = match target {
+            Ok(target) => target,
+            Err(err) => {
+                self.reply_to_client(http::error_response(400, "Bad Request"))
+                    .await?;
+                return Err(Box::new(err));
+            }
+        };
+        if !self.settings.commands.contains(&SocksCmd::Connect) {
+            self.reply_to_client(http::error_response(403, "Forbidden"))
+                .await?;
+            return Err(Box::new(HandshakeError::CommandNotAllowed));
+        }
+
+        // the request is handled like a SOCKS CONNECT from here on
+        let hs = SocksHandshake {
+            version: SocksVersion::V5,
+            cmd: SocksCmd::Connect,
+            addr,
+            port,
+        };
+        self.request = Some(hs.clone());
+        match self.dial_target(&hs).await {
+            Ok(outbound) => {
+                self.conn_state = ConnState::ConnEstablished(outbound.stream);
+                match forwarded_head {
+                    Some(head) => {
+                        let mut early_data = BytesMut::from(&head[..]);
+                        early_data.extend_from_slice(&buf);
+                        Ok(early_data)
+                    }
+                    None => {
+                        self.reply_to_client(http::connect_response()).await?;
+                        Ok(buf)
+                    }
+                }
+            }
+            Err(err) => {
+                debug!("Failed to reach {}:{}: {}", hs.addr, hs.port, err);
+                let (status, reason) = http::status_for(err.rep);
+                self.reply_to_client(http::error_response(status, reason))
+                    .await?;
+                Err(Box::new(err))
+            }
+        }
+    }
+
+    /// User from the Basic credentials of an HTTP request, sharing the SOCKS credentials.
+    ///
+    /// Requests without credentials are accepted only if no authentication is offered.
+    fn http_user(&self, req: &HttpRequest) -> std::result::Result<Option<String>, HandshakeError> {
+        let offers_user_pass = self
+            .settings
+            .methods
+            .contains(&SocksMethod::UsernamePassword);
+        let checker = self
+            .settings
+            .credentials
+            .as_ref()
+            .filter(|_| offers_user_pass);
+        match (checker, req.basic_auth()) {
+            (Some(checker), Some((username, password))) => {
+                if checker.check(&username, &password) {
+                    Ok(Some(username))
+                } else {
+                    Err(HandshakeError::AuthFailed)
+                }
+            }
+            _ if self.settings.methods.contains(&SocksMethod::NoAuth) => Ok(None),
+            _ => Err(HandshakeError::AuthFailed),
+        }
     }
 
     async fn handle_hs_advance(
@@ -116,11 +635,19 @@ impl ConnHandler {
         reply: Vec<u8>,
     ) -> Result<()> {
         match hs_builder.state() {
-            HandshakeState::Wait(_, _) => {
+            HandshakeState::Auth(_, _) | HandshakeState::Wait(_, _) => {
                 debug!("writing hs reply for client: {:?}", reply);
                 self.reply_to_client(reply).await
             }
-            HandshakeState::Finished(hs) => self.verify_target_conn(hs).await,
+            HandshakeState::Finished(hs) => {
+                self.user = hs_builder.user().map(String::from);
+                self.handle_request(hs).await
+            }
+            HandshakeState::Rejected(err) => {
+                debug!("rejecting client with hs reply: {:?}", reply);
+                self.reply_to_client(reply).await?;
+                Err(Box::new(err))
+            }
             HandshakeState::Init => {
                 unreachable!("this should never ever happen")
             }
@@ -128,8 +655,8 @@ impl ConnHandler {
     }
 
     async fn reply_to_client(&mut self, reply: Vec<u8>) -> Result<()> {
-        match self.socket.write(&reply).await {
-            Ok(_n_written) => Ok(()),
+        match self.socket.write_all(&reply).await {
+            Ok(()) => Ok(()),
             Err(err) => Err(Box::new(err)),
         }
     }
@@ -139,12 +666,12 @@ impl ConnHandler {
         hs: SocksHandshake,
         reply_status: ReplyField,
     ) -> Result<()> {
-        let reply = SocksReply::new(hs.version, reply_status, hs.atyp, hs.addr, hs.port);
+        let reply = SocksReply::new(hs.version, reply_status, hs.addr, hs.port);
         debug!(
             "Reply for client after target connection verification: {:?}",
             &reply.to_reply()
         );
-        self.socket.write(&reply.to_reply()).await?;
+        self.socket.write_all(&reply.to_reply()).await?;
         Ok(())
     }
 
@@ -153,44 +680,483 @@ impl ConnHandler {
         err: std::io::Error,
         hs: SocksHandshake,
     ) -> Result<()> {
-        match err.kind() {
-            ErrorKind::ConnectionRefused => {
-                self.connection_reply(hs, ReplyField::ConnectionRefused)
+        let reply_status = ReplyField::from(&err);
+        debug!("Err kind: {:?}, replying with {:?}", err, reply_status);
+        self.connection_reply(hs, reply_status).await?;
+        Err(Box::new(err))
+    }
+
+    /// Keeps only the addresses the access rules allow the client to reach.
+    fn allowed_addrs(&self, hs: &SocksHandshake, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        addrs
+            .into_iter()
+            .filter(|addr| self.allows(hs, *addr))
+            .collect()
+    }
+
+    fn allows(&self, hs: &SocksHandshake, addr: SocketAddr) -> bool {
+        let req = AclRequest {
+            client: self.client_addr.ip(),
+            user: self.user.as_deref(),
+            cmd: hs.cmd,
+            addr: &hs.addr,
+            ip: Some(addr.ip()),
+            port: addr.port(),
+        };
+        self.settings.acl.check(&req) == Action::Allow
+    }
+
+    async fn access_denied_reply(&mut self, hs: SocksHandshake) -> Result<()> {
+        let err = self.access_denied(&hs);
+        self.connection_reply(hs, err.rep).await?;
+        Err(Box::new(err))
+    }
+
+    async fn handle_request(&mut self, hs: SocksHandshake) -> Result<()> {
+        self.request = Some(hs.clone());
+        match hs.cmd {
+            SocksCmd::Connect => self.verify_target_conn(hs).await,
+            SocksCmd::Bind => self.bind(hs).await,
+            SocksCmd::UdpAssociate => self.udp_associate(hs).await,
+        }
+    }
+
+    async fn bound_reply(&mut self, version: SocksVersion, bound: SocketAddr) -> Result<()> {
+        let reply = SocksReply::new(
+            version,
+            ReplyField::Succeeded,
+            bound.ip().to_canonical().into(),
+            bound.port(),
+        );
+        debug!(
+            "Reply for client with bound address: {:?}",
+            &reply.to_reply()
+        );
+        self.socket.write_all(&reply.to_reply()).await?;
+        Ok(())
+    }
+
+    async fn udp_associate(&mut self, hs: SocksHandshake) -> Result<()> {
+        let client_ip = self.client_addr.ip();
+        let user = self.user.clone();
+        let acl_action =
+            self.settings
+                .acl
+                .check_without_destination(client_ip, user.as_deref(), hs.cmd);
+        if acl_action == Action::Deny {
+            return self.access_denied_reply(hs).await;
+        }
+
+        // the request carries the address the client will send datagrams from, if it knows it
+        let client_port = match hs.addr.ip() {
+            Some(ip) if !ip.is_unspecified() => hs.port,
+            _ => 0,
+        };
+        let local_ip = self.socket.local_addr()?.ip();
+
+        let client = SocketAddr::new(client_ip, client_port);
+        let udp_relay =
+            match UdpRelay::bind(local_ip, client, &*self.settings.dialer, user.as_deref()).await {
+                Ok(relay) => relay,
+                Err(err) => return self.connection_reply_with_error(err, hs).await,
+            };
+        let udp_relay = udp_relay
+            .with_idle_timeout(self.settings.timeouts.idle)
+            .with_resolver(self.settings.resolver.clone());
+        // every datagram is checked against the access rules once its target is known
+        let settings = self.settings.clone();
+        let udp_relay = udp_relay.with_filter(move |addr, target| {
+            let req = AclRequest {
+                client: client_ip,
+                user: user.as_deref(),
+                cmd: SocksCmd::UdpAssociate,
+                addr,
+                ip: Some(target.ip()),
+                port: target.port(),
+            };
+            settings.acl.check(&req) == Action::Allow
+        });
+        let bound = udp_relay.local_addr()?;
+        debug!("UDP relay for client {} bound to {}", client_ip, bound);
+
+        self.bound_reply(hs.version, bound).await?;
+        self.conn_state = ConnState::UdpAssociated(udp_relay);
+        Ok(())
+    }
+
+    async fn bind(&mut self, hs: SocksHandshake) -> Result<()> {
+        // DST.ADDR is the address we expect the inbound connection from
+        let peer_addrs = match self.resolve_in_time(&hs).await {
+            Ok(addrs) => addrs,
+            Err(err) => {
+                debug!("Failed to resolve expected peer {}: {:?}", hs.addr, err);
+                self.connection_reply(hs, ReplyField::HostUnreachable)
                     .await?;
-                Err(Box::new(err))
+                return Err(Box::new(err));
             }
-            _ => {
-                self.connection_reply(hs, ReplyField::SocksServerFailure)
-                    .await?;
-                debug!("Err kind: {:?}", err);
-                Err(Box::new(err))
+        };
+        let peer_addrs = self.allowed_addrs(&hs, peer_addrs);
+        if peer_addrs.is_empty() {
+            return self.access_denied_reply(hs).await;
+        }
+
+        let fallback_ip = self.socket.local_addr()?.ip();
+        let dialer = self.settings.dialer.clone();
+        let listener = match dialer
+            .bind_listener(&peer_addrs, fallback_ip, self.user.as_deref())
+            .await
+        {
+            Ok(listener) => listener,
+            Err(err) => return self.connection_reply_with_error(err, hs).await,
+        };
+        let bound = listener.local_addr()?;
+        debug!("Waiting for inbound connection on {}", bound);
+        self.bound_reply(hs.version, bound).await?;
+
+        let mut peek_buf = [0_u8; 1];
+        let accept_timeout = self.settings.timeouts.connect;
+        let accepted = {
+            // with an unspecified DST.ADDR the peer is only known once it connects
+            let allowed = |peer: SocketAddr| self.allows(&hs, peer);
+            let accept =
+                time::timeout(accept_timeout, accept_peer(&listener, &peer_addrs, allowed));
+            tokio::pin!(accept);
+            tokio::select! {
+                res = &mut accept => res,
+                res = self.socket.peek(&mut peek_buf) => match res {
+                    Ok(0) | Err(_) => {
+                        return Err(Box::new(std::io::Error::new(
+                            ErrorKind::ConnectionAborted,
+                            "client went away while waiting for inbound connection",
+                        )));
+                    }
+                    // the client sent data early, it will be relayed once the peer connects
+                    Ok(_) => accept.await,
+                },
+            }
+        };
+        let accepted = accepted.unwrap_or_else(|_| Err(timed_out("no inbound connection in time")));
+
+        match accepted {
+            Ok((peer_socket, peer)) => {
+                debug!("Accepted inbound connection from {}", peer);
+                self.bound_reply(hs.version, peer).await?;
+                self.conn_state = ConnState::ConnEstablished(Box::new(peer_socket));
+                Ok(())
             }
+            Err(err) => self.connection_reply_with_error(err, hs).await,
         }
     }
 
-    async fn verify_target_conn(&mut self, hs: SocksHandshake) -> Result<()> {
-        let addr = hs.to_addr();
-        debug!("Connecting to a target host at {:?}", addr);
+    async fn resolve_in_time(&self, hs: &SocksHandshake) -> std::io::Result<Vec<SocketAddr>> {
+        time::timeout(
+            self.settings.timeouts.connect,
+            resolve(&*self.settings.resolver, &hs.addr, hs.port),
+        )
+        .await
+        .unwrap_or_else(|_| Err(timed_out("resolving host timed out")))
+    }
 
-        match TcpStream::connect(addr).await {
-            Ok(target_socket) => {
-                self.connection_reply(hs, ReplyField::Succeeded).await?;
-                self.conn_state = ConnState::ConnEstablished(target_socket);
+    async fn verify_target_conn(&mut self, hs: SocksHandshake) -> Result<()> {
+        match self.dial_target(&hs).await {
+            Ok(Outbound { stream, local_addr }) => {
+                // BND.ADDR and BND.PORT tell the client which address we use to reach the target
+                self.bound_reply(hs.version, local_addr).await?;
+                self.conn_state = ConnState::ConnEstablished(stream);
                 Ok(())
             }
-            Err(err) => self.connection_reply_with_error(err, hs).await,
+            Err(err) => {
+                debug!("Failed to reach {}:{}: {}", hs.addr, hs.port, err);
+                self.connection_reply(hs, err.rep).await?;
+                Err(Box::new(err))
+            }
+        }
+    }
+
+    /// Connects to the target of a CONNECT request with the dialer.
+    async fn dial_target(&self, hs: &SocksHandshake) -> std::result::Result<Outbound, DialError> {
+        let dialer = self.settings.dialer.clone();
+        let addrs = if dialer.resolves_locally(&hs.addr, hs.port) {
+            let addrs = self
+                .resolve_in_time(hs)
+                .await
+                .map_err(|err| DialError::new(ReplyField::HostUnreachable, err))?;
+            let addrs = self.allowed_addrs(hs, addrs);
+            if addrs.is_empty() {
+                return Err(self.access_denied(hs));
+            }
+            debug!("Connecting to a target host at {:?}", addrs);
+            addrs
+        } else {
+            // domain names are left to the dialer to resolve
+            let req = AclRequest {
+                client: self.client_addr.ip(),
+                user: self.user.as_deref(),
+                cmd: hs.cmd,
+                addr: &hs.addr,
+                ip: hs.addr.ip(),
+                port: hs.port,
+            };
+            if self.settings.acl.check(&req) == Action::Deny {
+                return Err(self.access_denied(hs));
+            }
+            vec![]
+        };
+
+        let connect_timeout = self.settings.timeouts.connect;
+        time::timeout(
+            connect_timeout,
+            dialer.connect(&hs.addr, hs.port, &addrs, self.user.as_deref()),
+        )
+        .await
+        .unwrap_or_else(|_| Err(timed_out("connecting to target host timed out").into()))
+    }
+
+    fn access_denied(&self, hs: &SocksHandshake) -> DialError {
+        debug!(
+            "Access rules deny {:?} for {}:{} to client {}",
+            hs.cmd, hs.addr, hs.port, self.client_addr
+        );
+        DialError::new(ReplyField::ConnectionNotAllowed, AccessDenied)
+    }
+}
+
+/// Accepts the first inbound connection coming from one of the expected peer addresses.
+///
+/// When any peer is expected, its address is only known once it connects and has to pass
+/// `allowed` instead.
+async fn accept_peer(
+    listener: &TcpListener,
+    peer_addrs: &[SocketAddr],
+    allowed: impl Fn(SocketAddr) -> bool,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    let any_peer = peer_addrs.iter().all(|addr| addr.ip().is_unspecified());
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let peer_ip = peer.ip().to_canonical();
+        let expected = if any_peer {
+            allowed(SocketAddr::new(peer_ip, peer.port()))
+        } else {
+            peer_addrs.iter().any(|addr| addr.ip() == peer_ip)
+        };
+        if expected {
+            return Ok((socket, peer));
         }
+        debug!("Dropping inbound connection from unexpected peer {}", peer);
     }
 }
 
-pub async fn run(listener: TcpListener) {
-    let mut server = Server { listener };
+enum PendingListener {
+    Addr(SocketAddr, Option<Settings>),
+    Bound(TcpListener, Option<Settings>),
+}
+
+/// Builds a server to run inside another program.
+///
+/// Settings given through the `with_*` methods apply to every listener that was not
+/// added with its own settings.
+pub struct ServerBuilder {
+    listeners: Vec<PendingListener>,
+    settings: Settings,
+    // wrapped around the dialer on start, so that it does not matter which is set first
+    upstream: Option<Upstream>,
+    limits: Limits,
+    drain_timeout: Duration,
+    hooks: Arc<dyn Hooks>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self {
+            listeners: vec![],
+            settings: Settings::default(),
+            upstream: None,
+            limits: Limits::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            hooks: Arc::new(NoHooks),
+        }
+    }
+
+    /// Listens on `addr`, port 0 picks a free port.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.listeners.push(PendingListener::Addr(addr, None));
+        self
+    }
+
+    pub fn bind_with_settings(mut self, addr: SocketAddr, settings: Settings) -> Self {
+        self.listeners
+            .push(PendingListener::Addr(addr, Some(settings)));
+        self
+    }
+
+    /// Accepts connections on an already bound listener.
+    pub fn with_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(PendingListener::Bound(listener, None));
+        self
+    }
+
+    pub fn with_listener_settings(mut self, listener: TcpListener, settings: Settings) -> Self {
+        self.listeners
+            .push(PendingListener::Bound(listener, Some(settings)));
+        self
+    }
+
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Methods offered to clients, in order of preference.
+    pub fn with_methods(mut self, methods: Vec<SocksMethod>) -> Self {
+        self.settings.methods = methods;
+        self
+    }
+
+    pub fn with_credentials(mut self, credentials: Arc<dyn CredentialChecker>) -> Self {
+        self.settings.credentials = Some(credentials);
+        self
+    }
+
+    pub fn with_commands(mut self, commands: Vec<SocksCmd>) -> Self {
+        self.settings.commands = commands;
+        self
+    }
+
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.settings.acl = acl;
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.settings.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_dialer(mut self, dialer: Arc<dyn Dialer>) -> Self {
+        self.settings.dialer = dialer;
+        self
+    }
 
-    tokio::select! {
-      res = server.run() => {
-        if let Err(err) = res {
-          error!(cause = %err, "running server failed");
+    /// Sends CONNECT requests through upstream proxies, the dialer handles everything else
+    /// and the resolver resolves the proxies.
+    pub fn with_upstream(mut self, upstream: Upstream) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    /// Resolves the domain names clients ask for, and those of upstream proxies once the
+    /// server starts.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.settings.resolver = resolver;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Time open connections get to finish on shutdown before they are closed.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    pub fn with_hooks(mut self, hooks: Arc<dyn Hooks>) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Binds the listeners and starts accepting connections in the background.
+    pub async fn start(mut self) -> std::io::Result<ServerHandle> {
+        if self.listeners.is_empty() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "at least one listener is needed",
+            ));
+        }
+        if let Some(upstream) = self.upstream.take() {
+            let fallback = self.settings.dialer.clone();
+            let dialer = UpstreamDialer::new(upstream, fallback)
+                .with_resolver(self.settings.resolver.clone());
+            self.settings.dialer = Arc::new(dialer);
+        }
+
+        let mut listeners = vec![];
+        let mut local_addrs = vec![];
+        for pending in self.listeners {
+            let (listener, settings) = match pending {
+                PendingListener::Addr(addr, settings) => (TcpListener::bind(addr).await?, settings),
+                PendingListener::Bound(listener, settings) => (listener, settings),
+            };
+            local_addrs.push(listener.local_addr()?);
+            let settings = settings.unwrap_or_else(|| self.settings.clone());
+            listeners.push(Listener::new(listener, settings));
         }
-      }
+
+        let server = Server {
+            listeners,
+            limits: self.limits,
+            drain_timeout: self.drain_timeout,
+            hooks: self.hooks,
+        };
+        let shutdown = ShutdownHandle {
+            notify: Arc::new(Notify::new()),
+        };
+        let notify = shutdown.notify.clone();
+        let task = tokio::spawn(server.run(async move { notify.notified().await }));
+
+        Ok(ServerHandle {
+            local_addrs,
+            shutdown,
+            task,
+        })
+    }
+}
+
+/// Triggers the shutdown of a running server, can be cloned and sent to other tasks.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    notify: Arc<Notify>,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting connections and closes open ones after the drain timeout.
+    pub fn shutdown(&self) {
+        self.notify.notify_one();
+    }
+}
+
+/// Server running in the background.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    shutdown: ShutdownHandle,
+    task: JoinHandle<std::result::Result<(), JoinError>>,
+}
+
+impl ServerHandle {
+    /// Addresses the listeners are bound to, in the order they were added.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+    }
+
+    /// Waits until the server stopped, either after a shutdown or because it failed.
+    pub async fn wait(self) -> std::result::Result<(), JoinError> {
+        self.task.await?
     }
 }
@@ -0,0 +1,253 @@
+use std::{io, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ipnet::IpNet;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::{
+    acl::{DomainPattern, PortRange},
+    client::{self, Auth, ClientError},
+    dialer::{DialError, Dialer, Outbound},
+    handshake::{addr::SocksAddr, cmd::SocksCmd, reply_field::ReplyField},
+    http,
+    resolver::{resolve, Resolver},
+};
+
+#[derive(Error, Debug)]
+pub enum UpstreamError {
+    #[error("failed to connect to upstream proxy {proxy}: {source}")]
+    Unreachable { proxy: String, source: DialError },
+
+    #[error("upstream proxy {proxy} failed: {source}")]
+    Socks5 { proxy: String, source: ClientError },
+
+    #[error("upstream proxy {proxy} failed: {source}")]
+    Http { proxy: String, source: io::Error },
+
+    #[error("upstream proxy {proxy} answered with HTTP status {status}")]
+    HttpStatus { proxy: String, status: u16 },
+}
+
+impl From<&UpstreamError> for ReplyField {
+    fn from(err: &UpstreamError) -> Self {
+        match err {
+            // the last proxy tells us why it could not reach the target
+            UpstreamError::Socks5 {
+                source: ClientError::Rejected(rep),
+                ..
+            } => *rep,
+            UpstreamError::HttpStatus { status, .. } => match status {
+                403 | 407 => ReplyField::ConnectionNotAllowed,
+                404 | 502 => ReplyField::HostUnreachable,
+                504 => ReplyField::TtlExpired,
+                _ => ReplyField::SocksServerFailure,
+            },
+            _ => ReplyField::SocksServerFailure,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocol {
+    Socks5,
+    Http,
+}
+
+/// Upstream proxy outbound connections can be sent through.
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    pub name: String,
+    pub protocol: ProxyProtocol,
+    pub addr: SocksAddr,
+    pub port: u16,
+    pub auth: Auth,
+}
+
+/// Destinations sent through a chain of upstream proxies.
+///
+/// A route matches when all of its non-empty conditions match, each condition matches
+/// if any of its entries does. An empty chain connects directly.
+#[derive(Clone, Debug, Default)]
+pub struct Route {
+    pub via: Vec<Arc<Proxy>>,
+    pub destinations: Vec<IpNet>,
+    pub domains: Vec<DomainPattern>,
+    pub ports: Vec<PortRange>,
+}
+
+impl Route {
+    fn matches(&self, addr: &SocksAddr, port: u16) -> bool {
+        // domain names are resolved by the upstream, only IP addresses match networks
+        (self.destinations.is_empty()
+            || addr
+                .ip()
+                .is_some_and(|ip| self.destinations.iter().any(|net| net.contains(&ip))))
+            && (self.domains.is_empty()
+                || matches!(addr, SocksAddr::Domain(domain)
+                    if self.domains.iter().any(|pattern| pattern.matches(domain))))
+            && (self.ports.is_empty() || self.ports.iter().any(|range| range.contains(port)))
+    }
+}
+
+/// Ordered routes, the first matching one decides how to reach a destination.
+#[derive(Clone, Debug, Default)]
+pub struct Upstream {
+    pub routes: Vec<Route>,
+}
+
+impl Upstream {
+    /// Proxies to connect through, `None` if the destination is reached directly.
+    pub fn route(&self, addr: &SocksAddr, port: u16) -> Option<&[Arc<Proxy>]> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(addr, port))
+            .map(|route| &route.via[..])
+            .filter(|via| !via.is_empty())
+    }
+}
+
+/// Connects to the target through the chain of proxies.
+///
+/// The first proxy is reached with the dialer, then each proxy is asked to connect to
+/// the next one, the last one to the target. The local address of the returned connection
+/// is the one of the connection to the first proxy.
+pub async fn connect(
+    chain: &[Arc<Proxy>],
+    addr: &SocksAddr,
+    port: u16,
+    dialer: &dyn Dialer,
+    resolver: &dyn Resolver,
+    user: Option<&str>,
+) -> Result<Outbound, UpstreamError> {
+    let first = chain.first().expect("proxy chain is never empty");
+    let Outbound {
+        mut stream,
+        local_addr,
+    } = connect_first(first, dialer, resolver, user).await?;
+
+    for (i, proxy) in chain.iter().enumerate() {
+        let (next_addr, next_port) = match chain.get(i + 1) {
+            Some(next) => (&next.addr, next.port),
+            None => (addr, port),
+        };
+        debug!(
+            "Asking upstream proxy {} to connect to {}:{}",
+            proxy.name, next_addr, next_port
+        );
+        tunnel(&mut stream, proxy, next_addr, next_port).await?;
+    }
+    Ok(Outbound { stream, local_addr })
+}
+
+async fn connect_first(
+    proxy: &Proxy,
+    dialer: &dyn Dialer,
+    resolver: &dyn Resolver,
+    user: Option<&str>,
+) -> Result<Outbound, UpstreamError> {
+    let unreachable = |source| UpstreamError::Unreachable {
+        proxy: proxy.name.clone(),
+        source,
+    };
+    let addrs = if dialer.resolves_locally(&proxy.addr, proxy.port) {
+        resolve(resolver, &proxy.addr, proxy.port)
+            .await
+            .map_err(|err| unreachable(err.into()))?
+    } else {
+        vec![]
+    };
+    dialer
+        .connect(&proxy.addr, proxy.port, &addrs, user)
+        .await
+        .map_err(unreachable)
+}
+
+async fn tunnel<S>(
+    stream: &mut S,
+    proxy: &Proxy,
+    addr: &SocksAddr,
+    port: u16,
+) -> Result<(), UpstreamError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    match proxy.protocol {
+        ProxyProtocol::Socks5 => {
+            client::handshake(stream, SocksCmd::Connect, addr.clone(), port, &proxy.auth)
+                .await
+                .map_err(|source| UpstreamError::Socks5 {
+                    proxy: proxy.name.clone(),
+                    source,
+                })?;
+            Ok(())
+        }
+        ProxyProtocol::Http => {
+            let status = http_connect(stream, addr, port, &proxy.auth)
+                .await
+                .map_err(|source| UpstreamError::Http {
+                    proxy: proxy.name.clone(),
+                    source,
+                })?;
+            if !(200..300).contains(&status) {
+                return Err(UpstreamError::HttpStatus {
+                    proxy: proxy.name.clone(),
+                    status,
+                });
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Sends an HTTP CONNECT request and returns the status code of the response.
+async fn http_connect<S>(
+    stream: &mut S,
+    addr: &SocksAddr,
+    port: u16,
+    auth: &Auth,
+) -> io::Result<u16>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let authority = match addr {
+        SocksAddr::Ipv6(ip) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", addr, port),
+    };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Auth::UserPass(credentials) = auth {
+        let token = BASE64.encode(format!("{}:{}", credentials.username, credentials.password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte, whatever follows the header already belongs to the tunnel
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= http::MAX_HEADER_LEN {
+            return Err(invalid_response("response header is too long"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+
+    let status_line = header
+        .split(|&b| b == b'\n')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or_else(|| invalid_response("invalid status line"))?;
+    let mut parts = status_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status
+            .parse()
+            .map_err(|_| invalid_response("invalid status code")),
+        _ => Err(invalid_response("invalid status line")),
+    }
+}
+
+fn invalid_response(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}